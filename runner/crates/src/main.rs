@@ -0,0 +1,3 @@
+// Placeholder so the dependencies can be built into the image, the trampoline
+// swaps in the real program
+fn main() {}
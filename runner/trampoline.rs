@@ -0,0 +1,645 @@
+//! Compiles and runs a rust program inside the runner container
+//!
+//! The program and its metadata are read from stdin as frames, each frame is a
+//! header line `<kind> <length>\n` followed by `length` bytes, terminated by an
+//! `end 0\n` frame. See src/model/payload.rs in the bot for the writing side.
+//!
+//! Lines starting with ::ferris-bot:: on stderr are markers for the bot, they
+//! are parsed and stripped in src/model/outcome.rs. The `compiled` and
+//! `compile-failed` markers come last, anything after them is the program's
+//! and is never taken for a marker. Compiler diagnostics are written to stderr
+//! as JSON, one per line, see src/model/diagnostics.rs
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{exit, Command, Stdio};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Where the runner image keeps the vendored crates project
+const CRATES_DIR: &str = "/opt/ferris-bot/crates";
+
+/// The directory this run builds in, set once it has been created
+static WORKDIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// A decoded payload
+struct Payload {
+    source: Vec<u8>,
+    args: Vec<String>,
+    stdin: Vec<u8>,
+    channel: String,
+    edition: String,
+    mode: String,
+    rustc_flags: Vec<String>,
+    dependencies: Vec<Dependency>,
+    /// Files of a cargo project, from `file` frames of the form `<path>\n<contents>`
+    files: Vec<(PathBuf, Vec<u8>)>,
+    /// An error code to print the explanation of instead of running anything
+    explain: Option<String>,
+}
+
+/// A third party crate the program uses, from a `crate` frame of the form
+/// `<name> <version> <feature,feature>`
+struct Dependency {
+    name: String,
+    version: String,
+    features: Vec<String>,
+}
+
+impl Dependency {
+    fn parse(frame: &str) -> Option<Self> {
+        let mut parts = frame.split(' ');
+        let name = parts.next()?.to_string();
+        let version = parts.next()?.to_string();
+        let features: Vec<String> = parts
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(String::from)
+            .collect();
+
+        // Everything ends up in Cargo.toml, don't let anything break out of a string
+        let valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.^~*".contains(c))
+        };
+        if !valid(&name) || !valid(&version) || !features.iter().all(|f| valid(f)) {
+            return None;
+        }
+        Some(Dependency {
+            name,
+            version,
+            features,
+        })
+    }
+
+    fn manifest_line(&self) -> String {
+        let features: Vec<String> = self.features.iter().map(|f| format!("{:?}", f)).collect();
+        format!(
+            "{} = {{ version = {:?}, features = [{}] }}\n",
+            self.name,
+            self.version,
+            features.join(", ")
+        )
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload {
+            source: Vec::new(),
+            args: Vec::new(),
+            stdin: Vec::new(),
+            channel: String::from("stable"),
+            edition: String::from("2021"),
+            mode: String::from("debug"),
+            rustc_flags: Vec::new(),
+            dependencies: Vec::new(),
+            files: Vec::new(),
+            explain: None,
+        }
+    }
+}
+
+fn read_payload(input: &mut impl BufRead) -> io::Result<Payload> {
+    let mut payload = Payload::default();
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "missing end frame",
+            ));
+        }
+
+        let (kind, length) = header
+            .trim_end()
+            .split_once(' ')
+            .and_then(|(kind, length)| Some((kind.to_string(), length.parse::<u64>().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed frame header"))?;
+
+        let mut data = Vec::new();
+        input.take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated frame",
+            ));
+        }
+
+        match kind.as_str() {
+            "end" => return Ok(payload),
+            "source" => payload.source = data,
+            "arg" => payload
+                .args
+                .push(String::from_utf8_lossy(&data).into_owned()),
+            "stdin" => payload.stdin = data,
+            "channel" => payload.channel = String::from_utf8_lossy(&data).into_owned(),
+            "edition" => payload.edition = String::from_utf8_lossy(&data).into_owned(),
+            "mode" => payload.mode = String::from_utf8_lossy(&data).into_owned(),
+            "rustc-flag" => payload
+                .rustc_flags
+                .push(String::from_utf8_lossy(&data).into_owned()),
+            "crate" => match Dependency::parse(&String::from_utf8_lossy(&data)) {
+                Some(dependency) => payload.dependencies.push(dependency),
+                None => fail("malformed crate frame"),
+            },
+            "explain" => payload.explain = Some(String::from_utf8_lossy(&data).into_owned()),
+            "file" => match parse_file(data) {
+                Some(file) => payload.files.push(file),
+                None => fail("malformed file frame"),
+            },
+            // Frames from a newer bot are ignored rather than rejected
+            _ => {}
+        }
+    }
+}
+
+/// Splits a `file` frame into its path and contents, paths leaving the project
+/// are refused
+fn parse_file(mut data: Vec<u8>) -> Option<(PathBuf, Vec<u8>)> {
+    let newline = data.iter().position(|&b| b == b'\n')?;
+    let contents = data.split_off(newline + 1);
+    let path = PathBuf::from(std::str::from_utf8(&data[..newline]).ok()?);
+    let inside = path.components().count() > 0
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    inside.then_some((path, contents))
+}
+
+fn compile_failed() -> ! {
+    remove_workdir();
+    eprintln!("::ferris-bot::compile-failed");
+    exit(1)
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("trampoline: {}", message);
+    compile_failed()
+}
+
+/// Creates a directory of our own to build in. The local runtime shares the
+/// temporary directory between runs, and PIDs get reused, so an existing
+/// directory is never taken over
+fn create_workdir() -> &'static Path {
+    let temp = std::env::temp_dir();
+    for attempt in 0.. {
+        let workdir = temp.join(format!("ferris-bot-{}-{}", std::process::id(), attempt));
+        match std::fs::create_dir(&workdir) {
+            Ok(()) => return WORKDIR.get_or_init(|| workdir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => fail(&format!("could not create workdir: {}", e)),
+        }
+    }
+    unreachable!()
+}
+
+/// Removes the workdir along with everything built in it, if there is one
+fn remove_workdir() {
+    if let Some(workdir) = WORKDIR.get() {
+        if let Err(e) = std::fs::remove_dir_all(workdir) {
+            eprintln!("trampoline: could not remove workdir: {}", e);
+        }
+    }
+}
+
+/// Compiles a program without dependencies straight with rustc
+fn build_with_rustc(payload: &Payload, workdir: &Path) -> PathBuf {
+    let source = workdir.join("main.rs");
+    let binary = workdir.join("main");
+    if let Err(e) = std::fs::write(&source, &payload.source) {
+        fail(&format!("could not write source: {}", e));
+    }
+
+    let mut rustc = Command::new("rustc");
+    rustc
+        .arg(format!("+{}", payload.channel))
+        .args(["--edition", &payload.edition])
+        // The bot summarises the diagnostics and shows their colours
+        .args(["--error-format=json", "--json=diagnostic-rendered-ansi"])
+        // Diagnostics point at main.rs rather than our workdir
+        .current_dir(workdir);
+    // Same optimisations as cargo's release profile, which also turns off
+    // debug assertions and overflow checks
+    if payload.mode == "release" {
+        rustc.args(["-C", "opt-level=3"]);
+    }
+    // The bot checked these against its allowlist, flags with a value are
+    // sent as `-C opt-level=3` and need to be split back up
+    for flag in &payload.rustc_flags {
+        rustc.args(flag.split_whitespace());
+    }
+    rustc
+        .arg("-o")
+        .arg(&binary)
+        .arg("main.rs")
+        .stderr(Stdio::piped());
+    let mut build = match rustc.spawn() {
+        Ok(build) => build,
+        Err(e) => fail(&format!("could not run rustc: {}", e)),
+    };
+
+    if let Some(stderr) = build.stderr.take() {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            forward_diagnostic(&line);
+        }
+    }
+    match build.wait() {
+        Ok(status) if status.success() => binary,
+        Ok(_) => compile_failed(),
+        Err(e) => fail(&format!("could not run rustc: {}", e)),
+    }
+}
+
+/// Writes a line of compiler output to stderr for the bot. The explanations
+/// of error codes in JSON diagnostics are left out, they are long enough to
+/// make a few errors hit the output limit
+fn forward_diagnostic(line: &str) {
+    eprintln!("{}", without_explanations(line));
+}
+
+/// Replaces the value of every `"explanation"` in a JSON diagnostic with null
+fn without_explanations(line: &str) -> String {
+    const KEY: &str = "\"explanation\":\"";
+    let mut forwarded = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(KEY) {
+        let value = &rest[start + KEY.len()..];
+        // The string ends at the first quote that isn't escaped
+        let mut escaped = false;
+        let end = value.char_indices().find_map(|(i, c)| {
+            if c == '"' && !escaped {
+                return Some(i + 1);
+            }
+            escaped = c == '\\' && !escaped;
+            None
+        });
+        let end = match end {
+            Some(end) => end,
+            None => break,
+        };
+        forwarded.push_str(&rest[..start]);
+        forwarded.push_str("\"explanation\":null");
+        rest = &value[end..];
+    }
+    forwarded.push_str(rest);
+    forwarded
+}
+
+/// Compiles a program with third party crates, or a whole project, with cargo
+/// and returns the binary it built
+///
+/// The runner image has the allowlisted crates vendored and prebuilt in
+/// CRATES_DIR, its lockfile pins the vendored versions and its target
+/// directory saves us from compiling the dependencies again. Every run gets a
+/// fresh container, so writing to it is fine.
+fn build_with_cargo(payload: &Payload, project: &Path) -> PathBuf {
+    let crates = Path::new(CRATES_DIR);
+    let write = |path: &Path, contents: &[u8]| {
+        let path = project.join(path);
+        let written = std::fs::create_dir_all(path.parent().unwrap_or(project))
+            .and_then(|_| std::fs::write(&path, contents));
+        if let Err(e) = written {
+            fail(&format!("could not write {}: {}", path.display(), e));
+        }
+    };
+
+    if payload.files.is_empty() {
+        write(Path::new("src/main.rs"), &payload.source);
+    }
+    for (path, contents) in &payload.files {
+        write(path, contents);
+    }
+    // Projects can bring their own manifest, only vendored crates resolve though
+    if !project.join("Cargo.toml").exists() {
+        let mut manifest = format!(
+            "[package]\nname = \"main\"\nversion = \"0.1.0\"\nedition = {:?}\n\n[dependencies]\n",
+            payload.edition
+        );
+        // Depending on every vendored crate the way the image built them keeps
+        // the features of the crates they share the same, fewer crates would
+        // have cargo rebuild those within the run's time limit
+        let vendored = std::fs::read_to_string(crates.join("Cargo.toml")).ok();
+        match vendored.as_deref().and_then(dependencies_section) {
+            Some(dependencies) => manifest.push_str(dependencies),
+            None => {
+                for dependency in &payload.dependencies {
+                    manifest.push_str(&dependency.manifest_line());
+                }
+            }
+        }
+        write(Path::new("Cargo.toml"), manifest.as_bytes());
+    }
+    // Without the image's lockfile cargo would pick whatever it likes, which
+    // may not be vendored. Outside the image the crates come from the network
+    if !project.join("Cargo.lock").exists()
+        && std::fs::copy(crates.join("Cargo.lock"), project.join("Cargo.lock")).is_err()
+    {
+        eprintln!("trampoline: no vendored crates, resolving dependencies normally");
+    }
+    let target = if crates.exists() {
+        crates.join("target")
+    } else {
+        project.join("target")
+    };
+    let mut build = match cargo_command(payload, project, &target).spawn() {
+        Ok(build) => build,
+        Err(e) => fail(&format!("could not run cargo: {}", e)),
+    };
+
+    // Diagnostics go where rustc would have put them, everything else cargo
+    // says about the dependencies is left out
+    let mut executable = None;
+    if let Some(stdout) = build.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if line.contains("\"reason\":\"compiler-message\"") {
+                forward_diagnostic(&line);
+            } else if executable.is_none() {
+                executable = artifact_executable(&line);
+            }
+        }
+    }
+    match build.wait() {
+        Ok(status) if status.success() => {}
+        Ok(_) => compile_failed(),
+        Err(e) => fail(&format!("could not run cargo: {}", e)),
+    }
+
+    // Projects call their binary whatever they like, cargo tells us where it is
+    match executable {
+        Some(executable) => executable,
+        None => fail("cargo did not build a binary"),
+    }
+}
+
+/// What follows the `[dependencies]` header of a manifest, the vendored crates
+/// project keeps it as its last section
+fn dependencies_section(manifest: &str) -> Option<&str> {
+    let start = manifest.find("\n[dependencies]\n")? + "\n[dependencies]\n".len();
+    let section = &manifest[start..];
+    (!section.contains("\n[")).then_some(section)
+}
+
+/// The cargo invocation building `project` into `target`
+fn cargo_command(payload: &Payload, project: &Path, target: &Path) -> Command {
+    // The flags only go to the program's own binary, RUSTFLAGS would change
+    // them for the prebuilt dependencies too and have them all rebuilt
+    let mut cargo = Command::new("cargo");
+    cargo
+        .arg(format!("+{}", payload.channel))
+        .args(["rustc", "--bins", "--quiet", "--manifest-path"])
+        .arg(project.join("Cargo.toml"))
+        // Diagnostics and the binaries built come as JSON on stdout
+        .arg("--message-format=json-diagnostic-rendered-ansi")
+        .env("CARGO_TARGET_DIR", target)
+        .stdout(Stdio::piped());
+    if payload.mode == "release" {
+        cargo.arg("--release");
+    }
+    // Like for rustc, flags with a value need to be split back up
+    cargo.arg("--").args(
+        payload
+            .rustc_flags
+            .iter()
+            .flat_map(|flag| flag.split_whitespace()),
+    );
+    cargo
+}
+
+/// The binary a `compiler-artifact` message of cargo is about, if any. Our
+/// paths never need escaping in JSON, so the path is taken as is
+fn artifact_executable(line: &str) -> Option<PathBuf> {
+    if !line.contains("\"reason\":\"compiler-artifact\"") {
+        return None;
+    }
+    let start = line.find("\"executable\":\"")? + "\"executable\":\"".len();
+    let length = line[start..].find('"')?;
+    Some(PathBuf::from(&line[start..start + length]))
+}
+
+fn main() {
+    let payload = match read_payload(&mut io::stdin().lock()) {
+        Ok(payload) => payload,
+        Err(e) => fail(&format!("could not read payload: {}", e)),
+    };
+
+    let started = Instant::now();
+    if !["stable", "beta", "nightly"].contains(&payload.channel.as_str()) {
+        fail(&format!("unknown channel {}", payload.channel));
+    }
+    if !["debug", "release"].contains(&payload.mode.as_str()) {
+        fail(&format!("unknown mode {}", payload.mode));
+    }
+    if let Some(code) = &payload.explain {
+        if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+            fail("malformed explain frame");
+        }
+        let error = Command::new("rustc")
+            .arg(format!("+{}", payload.channel))
+            .arg("--explain")
+            .arg(code)
+            .exec();
+        fail(&format!("could not run rustc: {}", error));
+    }
+
+    let workdir = create_workdir();
+    let stdin = workdir.join("stdin");
+    if let Err(e) = std::fs::write(&stdin, &payload.stdin) {
+        fail(&format!("could not write stdin: {}", e));
+    }
+    let binary = if payload.dependencies.is_empty() && payload.files.is_empty() {
+        build_with_rustc(&payload, workdir)
+    } else {
+        build_with_cargo(&payload, &workdir.join("project"))
+    };
+    // Everything we were sent has been consumed, the program gets the stdin frame instead
+    let stdin = match File::open(&stdin) {
+        Ok(stdin) => stdin,
+        Err(e) => fail(&format!("could not open stdin: {}", e)),
+    };
+    let binary = match File::open(&binary) {
+        Ok(binary) => binary,
+        Err(e) => fail(&format!("could not open program: {}", e)),
+    };
+    // The open files outlive the workdir, so nothing is left behind once the
+    // program runs. The binary is run through its descriptor, which exec
+    // closes again
+    remove_workdir();
+    let mut program = Command::new(format!("/proc/self/fd/{}", binary.as_raw_fd()));
+    program.arg0("main");
+
+    // The last marker, the bot takes anything after it as the program's own stderr
+    eprintln!("::ferris-bot::compiled {}", started.elapsed().as_millis());
+    let e = program.args(&payload.args).stdin(stdin).exec();
+    eprintln!("trampoline: could not run program: {}", e);
+    exit(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: &str, data: &str) -> String {
+        format!("{} {}\n{}", kind, data.len(), data)
+    }
+
+    #[test]
+    fn reads_payloads() {
+        let input = [
+            frame("source", "fn main() {}\n"),
+            frame("arg", "a b"),
+            frame("arg", ""),
+            frame("stdin", "line\n"),
+            frame("channel", "nightly"),
+            frame("mode", "release"),
+            frame("rustc-flag", "-C overflow-checks=off"),
+            frame("crate", "rand 0.8.5 small_rng,std"),
+            frame("file", "src/lib.rs\npub fn f() {}\n"),
+            frame("from-the-future", "ignored"),
+            frame("end", ""),
+            frame("source", "after the end"),
+        ]
+        .concat();
+        let payload = read_payload(&mut input.as_bytes()).unwrap();
+        assert_eq!(payload.source, b"fn main() {}\n");
+        assert_eq!(payload.args, ["a b", ""]);
+        assert_eq!(payload.stdin, b"line\n");
+        assert_eq!(payload.channel, "nightly");
+        assert_eq!(payload.edition, "2021");
+        assert_eq!(payload.mode, "release");
+        assert_eq!(payload.rustc_flags, ["-C overflow-checks=off"]);
+        assert_eq!(payload.dependencies.len(), 1);
+        assert_eq!(payload.dependencies[0].features, ["small_rng", "std"]);
+        assert_eq!(
+            payload.files,
+            [(PathBuf::from("src/lib.rs"), b"pub fn f() {}\n".to_vec())]
+        );
+        assert!(payload.explain.is_none());
+    }
+
+    #[test]
+    fn refuses_broken_payloads() {
+        let error = |input: &str| read_payload(&mut input.as_bytes()).err().map(|e| e.kind());
+        assert_eq!(error(""), Some(io::ErrorKind::UnexpectedEof));
+        assert_eq!(
+            error(&frame("source", "fn main() {}")),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            error("source 100\nfn main"),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(error("source\nend 0\n"), Some(io::ErrorKind::InvalidData));
+        assert_eq!(
+            error("source -1\nend 0\n"),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn file_paths_stay_in_the_project() {
+        let file = |data: &str| parse_file(data.as_bytes().to_vec());
+        assert_eq!(
+            file("src/main.rs\nfn main() {}\n"),
+            Some((PathBuf::from("src/main.rs"), b"fn main() {}\n".to_vec()))
+        );
+        assert_eq!(
+            file("Cargo.toml\n"),
+            Some((PathBuf::from("Cargo.toml"), Vec::new()))
+        );
+        for data in [
+            "../evil\nx",
+            "src/../../evil\nx",
+            "/etc/passwd\nx",
+            "./main.rs\nx",
+            "\nx",
+            "no newline",
+        ] {
+            assert_eq!(file(data), None, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn crates_cannot_break_out_of_the_manifest() {
+        let dependency = Dependency::parse("serde 1 derive").unwrap();
+        assert_eq!(
+            dependency.manifest_line(),
+            "serde = { version = \"1\", features = [\"derive\"] }\n"
+        );
+        assert_eq!(Dependency::parse("rand 0.8").unwrap().features.len(), 0);
+        for frame in [
+            "",
+            "rand",
+            "rand \"0.8\"",
+            "rand 0.8 std\"]",
+            "rand = { path = \"/\" } 1",
+            "rand\n[patch] 1",
+        ] {
+            assert!(Dependency::parse(frame).is_none(), "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn cargo_passes_flags_like_rustc() {
+        let payload = Payload {
+            mode: String::from("release"),
+            rustc_flags: vec![
+                String::from("-C overflow-checks=off"),
+                String::from("--cfg=feature"),
+            ],
+            ..Payload::default()
+        };
+        let cargo = cargo_command(&payload, Path::new("/p"), Path::new("/t"));
+        let args: Vec<_> = cargo.get_args().collect();
+        let flags = args.iter().position(|&arg| arg == "--").unwrap();
+        assert!(args[..flags].contains(&"--release".as_ref()));
+        assert_eq!(
+            args[flags + 1..],
+            ["-C", "overflow-checks=off", "--cfg=feature"]
+        );
+    }
+
+    #[test]
+    fn depends_on_every_vendored_crate() {
+        let manifest = include_str!("crates/Cargo.toml");
+        let dependencies = dependencies_section(manifest).unwrap();
+        assert!(dependencies.starts_with("rand = "));
+        assert!(dependencies.contains("\ntokio = { version = \"1\", features = [\"full\"] }\n"));
+        assert_eq!(
+            dependencies_section("[package]\n\n[dependencies]\nrand = \"0.8\"\n\n[features]\n"),
+            None
+        );
+        assert_eq!(dependencies_section("[package]\nname = \"main\"\n"), None);
+    }
+
+    #[test]
+    fn finds_the_binary_cargo_built() {
+        let library =
+            r#"{"reason":"compiler-artifact","target":{"kind":["lib"]},"executable":null}"#;
+        let binary = r#"{"reason":"compiler-artifact","target":{"kind":["bin"]},"executable":"/tmp/target/debug/demo","fresh":false}"#;
+        let message = r#"{"reason":"compiler-message","executable":"/not/this"}"#;
+        assert_eq!(artifact_executable(library), None);
+        assert_eq!(
+            artifact_executable(binary),
+            Some(PathBuf::from("/tmp/target/debug/demo"))
+        );
+        assert_eq!(artifact_executable(message), None);
+    }
+
+    #[test]
+    fn leaves_out_explanations() {
+        let line = r#"{"message":"mismatched types","code":{"code":"E0308","explanation":"A \"quoted\" word\\"},"children":[{"explanation":"again"}]}"#;
+        assert_eq!(
+            without_explanations(line),
+            r#"{"message":"mismatched types","code":{"code":"E0308","explanation":null},"children":[{"explanation":null}]}"#
+        );
+        let unterminated = r#"{"explanation":"never ends"#;
+        assert_eq!(without_explanations(unterminated), unterminated);
+    }
+}
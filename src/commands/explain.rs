@@ -0,0 +1,236 @@
+use crate::model::explain::ErrorCode;
+use crate::model::outcome::ExecutionOutcome;
+use crate::model::runnable::Runnable;
+use crate::{Data, Error};
+use serenity::builder::{CreateComponents, CreateEmbed};
+use serenity::model::interactions::message_component::{ButtonStyle, MessageComponentInteraction};
+use serenity::model::interactions::{Interaction, InteractionResponseType};
+use serenity::model::user::User;
+
+use std::sync::Arc;
+
+/// Custom id of the buttons opening an explanation, followed by the error code
+pub const EXPLAIN_BUTTON: &str = "explain:";
+
+/// Custom id of the buttons turning the pages of an explanation, followed by
+/// `<code>:<page>`
+const PAGE_BUTTON: &str = "explain-page:";
+
+/// The pages of the explanation of `code`, `None` if rustc doesn't know it
+///
+/// Explanations come from `rustc --explain` in the runner image, so the first
+/// time a code is explained it waits for a turn like any other run
+async fn explanation(
+    data: &Data,
+    user: &User,
+    code: &ErrorCode,
+) -> Result<Option<Arc<Vec<String>>>, Error> {
+    if let Some(pages) = data.explanations.get(code) {
+        return Ok(pages);
+    }
+
+    let ticket = data.scheduler.enqueue(user.id.0)?;
+    let _permit = ticket.wait().await;
+    let result = code.run(None).await.map_err(Error::run)?;
+    match result.outcome {
+        ExecutionOutcome::Success => {
+            let explanation = String::from_utf8_lossy(&result.stdout);
+            Ok(data.explanations.insert(code.clone(), Some(&explanation)))
+        }
+        ExecutionOutcome::NonZeroExit(_)
+            if String::from_utf8_lossy(&result.stderr).contains("is not a valid error code") =>
+        {
+            Ok(data.explanations.insert(code.clone(), None))
+        }
+        outcome => Err(Error::Explanation(code.clone(), outcome)),
+    }
+}
+
+fn unknown_code(code: &ErrorCode) -> String {
+    format!("rustc has no explanation for {}.", code)
+}
+
+/// Shows page `page` of an explanation
+fn page_embed<'a>(
+    e: &'a mut CreateEmbed,
+    code: &ErrorCode,
+    pages: &[String],
+    page: usize,
+) -> &'a mut CreateEmbed {
+    e.title(code.to_string())
+        .url(format!(
+            "https://doc.rust-lang.org/error_codes/{}.html",
+            code
+        ))
+        .description(&pages[page]);
+    if pages.len() > 1 {
+        e.footer(|f| f.text(format!("Page {} of {}", page + 1, pages.len())));
+    }
+    e
+}
+
+/// Buttons to the previous and next page, none if everything fits on one
+fn page_buttons<'a>(
+    c: &'a mut CreateComponents,
+    code: &ErrorCode,
+    pages: usize,
+    page: usize,
+) -> &'a mut CreateComponents {
+    if pages <= 1 {
+        return c;
+    }
+    c.create_action_row(|row| {
+        row.create_button(|b| {
+            b.custom_id(format!(
+                "{}{}:{}",
+                PAGE_BUTTON,
+                code,
+                page.saturating_sub(1)
+            ))
+            .label("Previous")
+            .style(ButtonStyle::Secondary)
+            .disabled(page == 0)
+        })
+        .create_button(|b| {
+            b.custom_id(format!(
+                "{}{}:{}",
+                PAGE_BUTTON,
+                code,
+                (page + 1).min(pages - 1)
+            ))
+            .label("Next")
+            .style(ButtonStyle::Secondary)
+            .disabled(page + 1 >= pages)
+        })
+    })
+}
+
+/// Explains a rustc error code, like `rustc --explain`
+#[poise::command(slash_command)]
+pub async fn explain(
+    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    #[description = "The error code, e.g. E0382"] code: String,
+) -> Result<(), Error> {
+    let code = match ErrorCode::parse(&code) {
+        Some(code) => code,
+        None => {
+            poise::send_application_reply(ctx, |m| {
+                m.content("Error codes look like E0382.").ephemeral(true)
+            })
+            .await?;
+            return Ok(());
+        }
+    };
+
+    // An explanation that isn't cached yet takes a container to get
+    ctx.defer_response(false).await?;
+    let pages = match explanation(ctx.data, ctx.interaction.user(), &code).await? {
+        Some(pages) => pages,
+        None => {
+            poise::send_application_reply(ctx, |m| m.content(unknown_code(&code))).await?;
+            return Ok(());
+        }
+    };
+    poise::send_application_reply(ctx, |m| {
+        m.embed(|e| page_embed(e, &code, &pages, 0))
+            .components(|c| page_buttons(c, &code, pages.len(), 0))
+    })
+    .await?;
+    Ok(())
+}
+
+/// Handles the buttons of explanations, and the buttons on run results that
+/// open one
+pub async fn on_event(
+    discord: &serenity::client::Context,
+    event: &poise::Event<'_>,
+    data: &crate::Data,
+) -> Result<(), Error> {
+    let component = match event {
+        poise::Event::InteractionCreate {
+            interaction: Interaction::MessageComponent(component),
+        } => component,
+        _ => return Ok(()),
+    };
+    let custom_id = component.data.custom_id.as_str();
+    if let Some(code) = custom_id.strip_prefix(EXPLAIN_BUTTON) {
+        if let Some(code) = ErrorCode::parse(code) {
+            open_explanation(discord, data, component, code).await?;
+        }
+    } else if let Some(page) = custom_id.strip_prefix(PAGE_BUTTON) {
+        let page = page
+            .split_once(':')
+            .and_then(|(code, page)| Some((ErrorCode::parse(code)?, page.parse().ok()?)));
+        if let Some((code, page)) = page {
+            turn_page(discord, data, component, code, page).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answers a button on a run result with the explanation, only to whoever
+/// pressed it
+async fn open_explanation(
+    discord: &serenity::client::Context,
+    data: &crate::Data,
+    component: &MessageComponentInteraction,
+    code: ErrorCode,
+) -> Result<(), Error> {
+    component
+        .create_interaction_response(discord, |r| {
+            r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|d| d.ephemeral(true))
+        })
+        .await?;
+    match explanation(data, &component.user, &code).await {
+        Ok(Some(pages)) => {
+            component
+                .edit_original_interaction_response(discord, |r| {
+                    r.embed(|e| page_embed(e, &code, &pages, 0))
+                        .components(|c| page_buttons(c, &code, pages.len(), 0))
+                })
+                .await?;
+        }
+        Ok(None) => {
+            component
+                .edit_original_interaction_response(discord, |r| r.content(unknown_code(&code)))
+                .await?;
+        }
+        Err(e) => {
+            // The response is already there, so it says what went wrong itself
+            println!("Error explaining {}: {:?}", code, e);
+            component
+                .edit_original_interaction_response(discord, |r| r.content(e.to_string()))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows another page of the explanation a button is on
+async fn turn_page(
+    discord: &serenity::client::Context,
+    data: &crate::Data,
+    component: &MessageComponentInteraction,
+    code: ErrorCode,
+    page: usize,
+) -> Result<(), Error> {
+    component
+        .create_interaction_response(discord, |r| {
+            r.kind(InteractionResponseType::DeferredUpdateMessage)
+        })
+        .await?;
+    // Explanations are cached, unless the bot restarted since this one was shown
+    let pages = match explanation(data, &component.user, &code).await? {
+        Some(pages) => pages,
+        None => return Ok(()),
+    };
+    let page = page.min(pages.len() - 1);
+    component
+        .edit_original_interaction_response(discord, |r| {
+            r.embed(|e| page_embed(e, &code, &pages, page))
+                .components(|c| page_buttons(c, &code, pages.len(), page))
+        })
+        .await?;
+    Ok(())
+}
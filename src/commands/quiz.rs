@@ -21,17 +21,7 @@ pub async fn quiz(
     ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
     #[description = "The choice you want to choose"] question_number: i64,
 ) -> Result<(), Error> {
-    // Get the serenity interaction for the slash command
-    // TODO: error handling...
-    let slash_command = ctx.interaction.unwrap();
-
-    let channel = match slash_command.channel_id.to_channel(&ctx.discord.http).await {
-        Ok(channel) => channel,
-        Err(why) => {
-            println!("Error getting channel: {:?}", why);
-            return Ok(());
-        }
-    };
+    let channel = ctx.interaction.channel_id();
 
     let answers = [
         QuestionTF::True,
@@ -43,36 +33,33 @@ pub async fn quiz(
         QuestionTF::False,
     ];
 
-    if question_number > answers.len() as i64 {
-        println!("Question number out of bounds");
-        return Ok(());
+    if !(1..=answers.len() as i64).contains(&question_number) {
+        return Err(Error::UnknownQuestion {
+            number: question_number,
+            questions: answers.len(),
+        });
     }
+    let answer = &answers[(question_number - 1) as usize];
 
     // Get the current number of seconds since the epoch
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
+        .unwrap_or_default()
         .as_secs();
 
     // Load text from file question/q1.rs
-    let mut file = File::open(format!("questions/q{}.rs", question_number))?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    File::open(format!("questions/q{}.rs", question_number))
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(Error::Io)?;
 
     // Ask the question
-    ctx.interaction
-        .unwrap()
-        .create_interaction_response(&ctx.discord.http, |response| {
-            response
-                .kind(InteractionResponseType::ChannelMessageWithSource)
-                .interaction_response_data(|message| {
-                    message.content(MessageBuilder::new().push("Starting Quiz!").build())
-                })
-        })
-        .await?;
+    poise::send_application_reply(ctx, |m| {
+        m.content(MessageBuilder::new().push("Starting Quiz!").build())
+    })
+    .await?;
 
     let m = channel
-        .id()
         .send_message(&ctx.discord.http, |m| {
             m.content(
                 MessageBuilder::new()
@@ -85,25 +72,24 @@ pub async fn quiz(
             )
             .components(|c| c.add_action_row(QuestionTF::action_row()))
         })
-        .await
-        .unwrap();
+        .await?;
 
     // Wait for a responses within a certain amount of time
     let mut cib = m
-        .await_component_interactions(&ctx.discord)
+        .await_component_interactions(ctx.discord)
         .timeout(Duration::from_secs(QUESTION_TIME))
         .build();
 
     let mut correct_answers = Vec::new();
 
     while let Some(mci) = cib.next().await {
-        println!("{:?}", mci.data);
-        let question_choice = QuestionTF::from_str(&mci.data.custom_id).unwrap();
-
-        let member = mci.member.clone().unwrap();
+        let question_choice = match QuestionTF::from_str(&mci.data.custom_id) {
+            Ok(question_choice) => question_choice,
+            Err(_) => continue,
+        };
 
-        if question_choice == answers[(question_number - 1) as usize] {
-            correct_answers.push(member);
+        if question_choice == *answer {
+            correct_answers.push(mci.user.clone());
         }
 
         // Acknowledge the interaction and send a reply
@@ -116,36 +102,33 @@ pub async fn quiz(
                         .content(format!("You choose {}", question_choice))
                 })
         })
-        .await
-        .unwrap();
+        .await?;
     }
 
-    m.delete(&ctx.discord).await.unwrap();
+    m.delete(&ctx.discord).await?;
 
     // Write a message with people who got the question right
     let _m = channel
-        .id()
         .send_message(&ctx.discord, |m| {
             let mut builder = MessageBuilder::new();
 
             builder.push("The following people got the question right:\n\n");
 
-            for member in correct_answers {
-                builder.push(member.mention()).push(" ");
+            for user in correct_answers {
+                builder.push(user.mention()).push(" ");
             }
 
             m.content(
                 builder
                     .push("\n\nThe correct answer was: ")
-                    .push(answers[(question_number - 1) as usize].to_string())
+                    .push(answer.to_string())
                     .push("```rust\n")
                     .push(&contents)
                     .push("```")
                     .build(),
             )
         })
-        .await
-        .unwrap();
+        .await?;
 
     Ok(())
 }
@@ -1,49 +1,131 @@
+use crate::commands::explain::EXPLAIN_BUTTON;
+use crate::configuration;
+use crate::model::codeblock::{rust_blocks, CodeBlock};
+use crate::model::configurable::ConfigurableValue;
+use crate::model::container::get_container_settings;
+use crate::model::diagnostics::Diagnostics;
+use crate::model::eval::wrap_expression;
+use crate::model::outcome::{ExecutionOutcome, Markers, Timings};
+use crate::model::project::Project;
+use crate::model::render;
+use crate::model::replies::TrackedReply;
 use crate::model::runnable::*;
-use crate::Error;
+use crate::model::scheduler::Ticket;
+use crate::model::toolchain::{normalise_rustc_flags, parse_rustc_flags, Channel, Edition, Mode};
+use crate::{Context, Error};
+use serenity::builder::CreateAllowedMentions;
+use serenity::model::channel::{Attachment, Message};
+use serenity::model::id::{ChannelId, MessageId};
+use serenity::model::interactions::message_component::ButtonStyle;
+use serenity::model::interactions::InteractionResponseType;
+use serenity::model::user::User;
 use serenity::prelude::Mentionable;
+use tokio::sync::watch;
 
-use std::io::ErrorKind;
-
-/// Given some stdout or stderr data, format it so that it can be rendered by discord
-fn format_output(response: String, syntax_highlight: Option<&str>) -> String {
-    if response.len() < 1000 {
-        // Response falls within size constraints
-        return format!("```{}\n{}\n```", syntax_highlight.unwrap_or(""), response);
-    } else {
-        // For UX, truncate components to 1000 chars... should be long enough
-        let short_repsonse = &response[0..1000];
-        return format!(
-            "```{}\n{}[TRUNCATED]```",
-            syntax_highlight.unwrap_or(""),
-            short_repsonse
-        );
+use std::time::{Duration, Instant};
+
+/// How often the reply is edited with the output of a run that is still going
+const LIVE_EDIT_INTERVAL: Duration = Duration::from_millis(2000);
+
+/// Most error codes of a failed build that get a button to explain them, all
+/// of them fit on one row
+const MAX_EXPLAIN_BUTTONS: usize = 5;
+
+/// The full text of output too long for its field, `None` if the field shows
+/// all of it
+fn output_attachment(output: &str) -> Option<Vec<u8>> {
+    if render::output_fits(output, render::FIELD_VALUE_LIMIT) {
+        return None;
     }
+    Some(attachment(output))
 }
 
-async fn reply(
-    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+/// Text as the contents of an attached file, cut off after
+/// `MAX_ATTACHMENT_SIZE` bytes
+fn attachment(text: &str) -> Vec<u8> {
+    // Colours only get in the way in a file
+    let text = &render::strip_ansi(text);
+    let max_size = configuration::MAX_ATTACHMENT_SIZE.value() as usize;
+    if text.len() <= max_size {
+        return text.as_bytes().to_vec();
+    }
+    let mut end = max_size;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[... cut off after {} bytes ...]\n", &text[..end], end).into_bytes()
+}
+
+/// What a run shows in its reply besides the output
+struct Submission {
+    /// Shown in the Code field
     code: String,
+    /// Syntax highlighting used for the Code field
+    language: &'static str,
+    /// Shown in the footer, e.g. "Rust stable (2021 edition, debug)"
+    toolchain: String,
+    /// Whether the code is an expression whose value is all the reply shows,
+    /// see [`eval_result`]
+    compact: bool,
+}
+
+impl Submission {
+    fn new(code: String, channel: Channel, edition: Edition, mode: Mode) -> Self {
+        Submission {
+            code,
+            language: "rs",
+            toolchain: format!("Rust {} ({} edition, {})", channel, edition, mode),
+            compact: false,
+        }
+    }
+
+    fn from_program(program: &Program) -> Self {
+        Submission::new(
+            program.code.clone(),
+            program.channel,
+            program.edition,
+            program.mode,
+        )
+    }
+}
+
+/// Embed fields showing the code, a summary of the compiler diagnostics and
+/// whatever output there is, fitted in an embed whose title and footer take up
+/// `reserved` characters
+fn output_fields(
+    submission: &Submission,
+    diagnostics: &Diagnostics,
     stdout: Option<String>,
     stderr: Option<String>,
-) -> Result<(), Error> {
-    let interaction = ctx.interaction.unwrap();
-    let channel = match interaction.channel_id.to_channel(&ctx.discord.http).await {
-        Ok(channel) => channel,
-        Err(why) => {
-            println!("Error getting channel: {:?}", why);
-            return Ok(());
-        }
-    };
-    let member = interaction.member.clone().unwrap();
+    reserved: usize,
+) -> Vec<(String, String, bool)> {
+    let mut fields = vec![(
+        "Code",
+        render::code_block(
+            &submission.code,
+            submission.language,
+            render::FIELD_VALUE_LIMIT,
+        ),
+        true,
+    )];
 
-    // TODO: probably a nicer way to do this
-    let mut fields = vec![("Code", format_output(code, Some("rs")), true)];
+    if let Some(summary) = diagnostics.summary() {
+        fields.push((
+            "Diagnostics",
+            render::output_block(&summary, render::FIELD_VALUE_LIMIT),
+            false,
+        ));
+    }
 
     // If stdout is present, add it to the fields
     if let Some(stdout) = stdout {
         // Ensure that the stdout is not empty
         if !stdout.is_empty() {
-            fields.push(("Output", format_output(stdout, None), false));
+            fields.push((
+                "Output",
+                render::output_block(&stdout, render::FIELD_VALUE_LIMIT),
+                false,
+            ));
         }
     }
 
@@ -51,114 +133,1000 @@ async fn reply(
     if let Some(stderr) = stderr {
         // Ensure stderr is not empty
         if !stderr.is_empty() {
-            fields.push(("Error", format_output(stderr, None), false));
+            fields.push((
+                "Error",
+                render::output_block(&stderr, render::FIELD_VALUE_LIMIT),
+                false,
+            ));
         }
     }
 
-    channel
-        .id()
-        .send_message(&ctx.discord.http, |m| {
-            m.content(format!("{} ran", member.mention()));
+    render::fit_fields(fields, reserved)
+}
+
+/// Splits the compiler diagnostics out of stderr and decodes the rest of it.
+/// Only the first `build_output` bytes were written before the program started,
+/// so JSON the program prints is never taken for a diagnostic
+fn split_diagnostics(stderr: &[u8], build_output: usize, hex_dump: bool) -> (Diagnostics, String) {
+    let (build, program) = stderr.split_at(build_output.min(stderr.len()));
+    let (diagnostics, mut remaining) =
+        Diagnostics::extract(&render::decode_output(build, hex_dump));
+    remaining.push_str(&render::decode_output(program, hex_dump));
+    (diagnostics, remaining)
+}
+
+/// What an eval shows instead of the embed fields of a run: the expression
+/// and `= <value>`, or the first error if it didn't compile
+fn eval_result(
+    expression: &str,
+    outcome: ExecutionOutcome,
+    diagnostics: &Diagnostics,
+    stdout: &str,
+    stderr: &str,
+) -> String {
+    if let Some(error) = diagnostics.first_error() {
+        return render::output_block(&error, render::DESCRIPTION_LIMIT);
+    }
+    let result = match outcome {
+        ExecutionOutcome::Success => format!("= {}", stdout.trim_end()),
+        // A panic says what went wrong on stderr, a timeout doesn't
+        _ if !stderr.trim().is_empty() => stderr.trim_end().to_string(),
+        outcome => outcome.to_string(),
+    };
+    render::code_block(
+        &format!("{}\n{}", expression.trim(), result),
+        "rs",
+        render::DESCRIPTION_LIMIT,
+    )
+}
+
+/// Turns the placeholder message of a run into its final result
+#[allow(clippy::too_many_arguments)]
+async fn reply(
+    discord: &serenity::client::Context,
+    author: &User,
+    message: &mut Message,
+    submission: &Submission,
+    outcome: ExecutionOutcome,
+    timings: Timings,
+    diagnostics: Diagnostics,
+    stdout: Option<String>,
+    stderr: Option<String>,
+) -> Result<(), Error> {
+    if submission.compact {
+        let result = eval_result(
+            &submission.code,
+            outcome,
+            &diagnostics,
+            stdout.as_deref().unwrap_or_default(),
+            stderr.as_deref().unwrap_or_default(),
+        );
+        message
+            .edit(discord, |m| {
+                m.allowed_mentions(|am| allowed_mentions(am, Some(author)));
+                m.content(format!("{} evaluated", author.mention()));
+                m.embed(|e| e.colour(outcome.colour()).description(result))
+            })
+            .await?;
+        return Ok(());
+    }
+
+    // The embed only has room for the start and end of long output and a
+    // summary of the diagnostics, the rest can be read in the attached files
+    let mut attachments: Vec<(Vec<u8>, &str)> = [(&stdout, "stdout.txt"), (&stderr, "stderr.txt")]
+        .into_iter()
+        .filter_map(|(output, filename)| {
+            output
+                .as_deref()
+                .and_then(output_attachment)
+                .map(|data| (data, filename))
+        })
+        .collect();
+    let rendered = diagnostics.rendered();
+    if !rendered.is_empty() {
+        attachments.push((attachment(&rendered), "diagnostics.txt"));
+    }
+    // Files of a previous run of an edited message are replaced
+    let previous_attachments: Vec<_> = message.attachments.iter().map(|a| a.id).collect();
+    let title = render::truncate(&outcome.to_string(), render::TITLE_LIMIT);
+    let footer = render::truncate(
+        &format!("{} · {}", submission.toolchain, timings),
+        render::FOOTER_LIMIT,
+    );
+    let reserved = title.chars().count() + footer.chars().count();
+    let codes: Vec<String> = diagnostics
+        .error_codes()
+        .into_iter()
+        .take(MAX_EXPLAIN_BUTTONS)
+        .map(String::from)
+        .collect();
+    let fields = output_fields(submission, &diagnostics, stdout, stderr, reserved);
+
+    message
+        .edit(discord, |m| {
+            m.allowed_mentions(|am| allowed_mentions(am, Some(author)));
+            for id in previous_attachments {
+                m.remove_existing_attachment(id);
+            }
+            for (data, filename) in &attachments {
+                m.attachment((data.as_slice(), *filename));
+            }
+            m.content(format!("{} ran", author.mention()));
             m.embed(|e| {
+                e.title(title);
+                e.colour(outcome.colour());
                 e.fields(fields);
+                e.footer(|f| f.text(footer));
                 e
+            });
+            // Buttons of a previous run of an edited message go away too
+            m.components(|c| {
+                if !codes.is_empty() {
+                    c.create_action_row(|row| {
+                        for code in &codes {
+                            row.create_button(|b| {
+                                b.custom_id(format!("{}{}", EXPLAIN_BUTTON, code))
+                                    .label(format!("Explain {}", code))
+                                    .style(ButtonStyle::Secondary)
+                            });
+                        }
+                        row
+                    });
+                }
+                c
             })
         })
         .await?;
     Ok(())
 }
 
+/// Keeps the placeholder message of a run updated with its output until the run
+/// finishes, edits are throttled to stay within Discord's rate limits
+async fn stream_output(
+    discord: &serenity::client::Context,
+    message: &mut Message,
+    submission: &Submission,
+    mut live: watch::Receiver<LiveOutput>,
+) -> Result<(), Error> {
+    let mut next_edit = Instant::now() + LIVE_EDIT_INTERVAL;
+
+    // changed() fails once the run is over and has dropped its end of the channel
+    while live.changed().await.is_ok() {
+        // Let more output pile up until we are allowed to edit again
+        let wait = tokio::time::sleep_until(next_edit.into());
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                changed = live.changed() => if changed.is_err() {
+                    return Ok(());
+                },
+            }
+        }
+
+        // Output can be cut anywhere, including in the middle of a character
+        let hex_dump = configuration::HEX_DUMP_BINARY_OUTPUT.value();
+        let (stdout, diagnostics, stderr) = {
+            let output = live.borrow_and_update();
+            let (markers, mut stderr) = Markers::extract(&output.stderr);
+            // A diagnostic still being written would show up as half a line of
+            // JSON, only while building though
+            let finished = stderr
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |newline| newline + 1);
+            if markers.build_output == stderr.len() && stderr[finished..].starts_with(b"{") {
+                stderr.truncate(finished);
+            }
+            let (diagnostics, stderr) = split_diagnostics(&stderr, markers.build_output, hex_dump);
+            (
+                render::decode_output(&output.stdout, hex_dump),
+                diagnostics,
+                stderr,
+            )
+        };
+        let fields = output_fields(
+            submission,
+            &diagnostics,
+            Some(stdout),
+            Some(stderr),
+            "Running...".len(),
+        );
+
+        message
+            .edit(discord, |m| {
+                m.allowed_mentions(|am| allowed_mentions(am, None));
+                m.embed(|e| {
+                    e.title("Running...");
+                    e.fields(fields);
+                    e
+                })
+            })
+            .await?;
+        next_edit = Instant::now() + LIVE_EDIT_INTERVAL;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, poise::Modal)]
-#[allow(dead_code)] // fields only used for Debug print
 struct RunModal {
     #[name = "Code you want to run"]
     #[placeholder = "fn main() {\n    println!(\"Hello, world!\");\n}"]
     #[paragraph]
     code_to_run: String,
+    #[name = "Arguments"]
+    #[placeholder = "--verbose \"hello world\""]
+    arguments: Option<String>,
+    #[name = "Standard input"]
+    #[paragraph]
+    stdin: Option<String>,
+}
+
+/// Splits a line of arguments on whitespace, quotes can be used to keep
+/// whitespace inside an argument
+fn split_arguments(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// `/run` and `!run`, poise looks commands up by name for both kinds of
+/// invocation so they have to be a single command
+pub fn run() -> poise::Command<crate::Data, Error> {
+    poise::Command {
+        prefix_action: run_prefix().prefix_action,
+        ..run_slash()
+    }
 }
 
 /// Runs whatever code you throw at it
-#[poise::command(slash_command)]
-pub async fn run(
+#[poise::command(slash_command, rename = "run")]
+pub async fn run_slash(
     ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    #[rename = "channel"]
+    #[description = "Toolchain channel to compile with, defaults to stable"]
+    toolchain_channel: Option<Channel>,
+    #[description = "Edition to compile with, defaults to 2021"] edition: Option<Edition>,
+    #[description = "Compile with or without optimisations, defaults to debug"] mode: Option<Mode>,
+    #[description = "Extra rustc flags, e.g. -C overflow-checks=off"] flags: Option<String>,
 ) -> Result<(), Error> {
     use poise::Modal as _;
 
-    let _channel = match ctx
-        .interaction
-        .unwrap()
-        .channel_id
-        .to_channel(&ctx.discord.http)
-        .await
-    {
-        Ok(channel) => channel,
-        Err(why) => {
-            println!("Error getting channel: {:?}", why);
+    // Check the flags before anything else, the modal has to be our first response
+    let allowlist: Vec<String> = configuration::RUSTC_FLAGS_ALLOWLIST
+        .value()
+        .split(',')
+        .map(|entry| normalise_rustc_flags(entry).join(" "))
+        .collect();
+    let rustc_flags = match parse_rustc_flags(flags.as_deref().unwrap_or(""), &allowlist) {
+        Ok(rustc_flags) => rustc_flags,
+        Err(e) => {
+            poise::send_application_reply(ctx, |m| {
+                m.content(format!(
+                    "{}, allowed flags are `{}`",
+                    e,
+                    allowlist.join("`, `")
+                ))
+                .ephemeral(true)
+            })
+            .await?;
             return Ok(());
         }
     };
 
     let modal_data = RunModal::execute(ctx).await?;
-    let raw_code = modal_data.code_to_run;
+    let program = Program {
+        code: modal_data.code_to_run,
+        args: modal_data
+            .arguments
+            .as_deref()
+            .map(split_arguments)
+            .unwrap_or_default(),
+        stdin: modal_data.stdin,
+        channel: toolchain_channel.unwrap_or_default(),
+        edition: edition.unwrap_or_default(),
+        mode: mode.unwrap_or_default(),
+        rustc_flags,
+    };
+    let submission = Submission::from_program(&program);
+
+    run_and_reply(ctx.into(), &program, submission, None).await?;
+    Ok(())
+}
+
+/// Runs a cargo project uploaded as an archive or as .rs files and a Cargo.toml
+#[poise::command(slash_command, rename = "run-project")]
+#[allow(clippy::too_many_arguments)]
+pub async fn run_project(
+    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    #[description = "A .zip or .tar.gz of the project, or its main.rs"] file: Attachment,
+    #[description = "Another .rs file or the Cargo.toml"] file2: Option<Attachment>,
+    #[description = "Another .rs file or the Cargo.toml"] file3: Option<Attachment>,
+    #[description = "Another .rs file or the Cargo.toml"] file4: Option<Attachment>,
+    #[description = "Arguments passed to the program"] arguments: Option<String>,
+    #[rename = "channel"]
+    #[description = "Toolchain channel to compile with, defaults to stable"]
+    toolchain_channel: Option<Channel>,
+    #[description = "Edition to compile with, defaults to 2021"] edition: Option<Edition>,
+    #[description = "Compile with or without optimisations, defaults to debug"] mode: Option<Mode>,
+) -> Result<(), Error> {
+    // Downloading can take a while, Discord wants an answer within 3 seconds
+    poise::send_application_reply(ctx, |m| {
+        m.content("Unpacking your project...").ephemeral(true)
+    })
+    .await?;
+
+    // The whole project has to fit in a payload, so that limit applies to the
+    // uploads and to what they unpack into
+    let max_size = get_container_settings().max_program_size as usize;
+    let mut project = Project {
+        args: arguments
+            .as_deref()
+            .map(split_arguments)
+            .unwrap_or_default(),
+        channel: toolchain_channel.unwrap_or_default(),
+        edition: edition.unwrap_or_default(),
+        mode: mode.unwrap_or_default(),
+        ..Default::default()
+    };
+    for attachment in [Some(file), file2, file3, file4].into_iter().flatten() {
+        if attachment.size as usize > max_size {
+            let message = format!(
+                "`{}` is {} bytes, the limit is {} bytes.",
+                attachment.filename, attachment.size, max_size
+            );
+            poise::send_application_reply(ctx, |m| m.content(message).ephemeral(true)).await?;
+            return Ok(());
+        }
+        let contents = attachment.download().await?;
+        if let Err(e) = project.add_attachment(&attachment.filename, contents, max_size) {
+            poise::send_application_reply(ctx, |m| {
+                m.content(render::message_content(&e.to_string()))
+                    .ephemeral(true)
+            })
+            .await?;
+            return Ok(());
+        }
+    }
+    if let Err(e) = project.finish() {
+        poise::send_application_reply(ctx, |m| {
+            m.content(render::message_content(&e.to_string()))
+                .ephemeral(true)
+        })
+        .await?;
+        return Ok(());
+    }
+
+    // The reply lists the files instead of showing all of their code
+    let listing = project
+        .files
+        .iter()
+        .map(|file| format!("{} ({} bytes)", file.path, file.contents.len()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let submission = Submission {
+        language: "",
+        ..Submission::new(listing, project.channel, project.edition, project.mode)
+    };
+
+    run_and_reply(ctx.into(), &project, submission, None).await?;
+    Ok(())
+}
 
-    // This leverages the runnable trait we created for executing arbitrary strings of code
-    let run_result = raw_code.run().await;
+/// Prints the value of an expression, statements before it run first
+#[poise::command(slash_command)]
+pub async fn eval(
+    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    #[description = "e.g. let v = vec![1, 2]; v.iter().rev().collect::<Vec<_>>()"]
+    expression: String,
+    #[rename = "channel"]
+    #[description = "Toolchain channel to compile with, defaults to stable"]
+    toolchain_channel: Option<Channel>,
+    #[description = "Edition to compile with, defaults to 2021"] edition: Option<Edition>,
+) -> Result<(), Error> {
+    poise::send_application_reply(ctx, |m| m.content("Evaluating...").ephemeral(true)).await?;
+
+    let program = Program {
+        code: wrap_expression(&expression),
+        channel: toolchain_channel.unwrap_or_default(),
+        edition: edition.unwrap_or_default(),
+        ..Default::default()
+    };
+    // Only the expression is shown, not the main it was wrapped in
+    let submission = Submission {
+        code: expression,
+        compact: true,
+        ..Submission::from_program(&program)
+    };
+    run_and_reply(ctx.into(), &program, submission, None).await?;
+    Ok(())
+}
 
-    match run_result {
-        Ok(output) => {
-            let mut stdout = String::new();
-            let mut stderr = String::new();
+/// Most code blocks offered to choose from when a message has several
+const MAX_BLOCK_CHOICES: usize = 5;
 
-            if !output.stdout.is_empty() {
-                stdout = match String::from_utf8(output.stdout) {
-                    Ok(v) => v,
-                    Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                };
+/// How long the choice between several code blocks is offered
+const BLOCK_CHOICE_TIME: Duration = Duration::from_secs(60);
 
-                println!("Got stdout\n\"{}\"", stdout);
-            } else {
-                println!("No stdout");
-            }
+/// Runs a rust code block from a message
+#[poise::command(context_menu_command = "Run Rust code")]
+pub async fn run_message(
+    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    message: Message,
+) -> Result<(), Error> {
+    let blocks = rust_blocks(&message.content);
+    let block = match blocks.len() {
+        0 => {
+            poise::send_application_reply(ctx, |m| {
+                m.content("That message has no rust code blocks.")
+                    .ephemeral(true)
+            })
+            .await?;
+            return Ok(());
+        }
+        1 => {
+            poise::send_application_reply(ctx, |m| {
+                m.content("Running it, the result will be posted as a reply to the message.")
+                    .ephemeral(true)
+            })
+            .await?;
+            0
+        }
+        _ => match choose_block(ctx, &blocks).await? {
+            Some(block) => block,
+            None => return Ok(()),
+        },
+    };
+
+    let program = Program::from(blocks[block].code.to_string());
+    let submission = Submission::from_program(&program);
+    // The message may not be ours, so it is left without a reaction
+    let source = Source {
+        message: &message,
+        block,
+        react: false,
+    };
+    run_and_reply(ctx.into(), &program, submission, Some(source)).await?;
+    Ok(())
+}
+
+/// Asks which of several code blocks to run, `None` if nobody picked one in time
+async fn choose_block(
+    ctx: poise::ApplicationContext<'_, crate::Data, crate::Error>,
+    blocks: &[CodeBlock<'_>],
+) -> Result<Option<usize>, Error> {
+    let handle = poise::send_application_reply(ctx, |m| {
+        m.content("That message has several code blocks, which one should run?")
+            .ephemeral(true)
+            .components(|c| {
+                c.create_action_row(|row| {
+                    for (index, block) in blocks.iter().take(MAX_BLOCK_CHOICES).enumerate() {
+                        // The first line usually tells the blocks apart
+                        let first_line = block.code.lines().next().unwrap_or("").trim();
+                        let label: String = format!("{}: {}", index + 1, first_line)
+                            .chars()
+                            .take(80)
+                            .collect();
+                        row.create_button(|b| {
+                            b.custom_id(format!("block-{}", index))
+                                .label(label)
+                                .style(ButtonStyle::Secondary)
+                        });
+                    }
+                    row
+                })
+            })
+    })
+    .await?;
 
-            if !output.stderr.is_empty() {
-                stderr = match String::from_utf8(output.stderr) {
-                    Ok(v) => v,
-                    Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                };
-                println!("Got stderr \"{}\"", stderr);
-            } else {
-                println!("No stderr");
+    let choice = handle
+        .message()
+        .await?
+        .await_component_interaction(ctx.discord)
+        .timeout(BLOCK_CHOICE_TIME)
+        .await;
+    let choice = match choice {
+        Some(choice) => choice,
+        None => {
+            if let poise::ApplicationCommandOrAutocompleteInteraction::ApplicationCommand(
+                interaction,
+            ) = ctx.interaction
+            {
+                interaction
+                    .edit_original_interaction_response(&ctx.discord, |r| {
+                        r.content("No code block was picked.").components(|c| c)
+                    })
+                    .await?;
             }
+            return Ok(None);
+        }
+    };
+
+    let index = choice
+        .data
+        .custom_id
+        .strip_prefix("block-")
+        .and_then(|index| index.parse::<usize>().ok())
+        .filter(|&index| index < blocks.len());
+    choice
+        .create_interaction_response(&ctx.discord, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| {
+                    d.content("Running it, the result will be posted as a reply to the message.")
+                        .components(|c| c)
+                })
+        })
+        .await?;
+    Ok(index)
+}
+
+/// Runs the rust code block in your message
+#[poise::command(prefix_command, rename = "run")]
+async fn run_prefix(
+    ctx: poise::PrefixContext<'_, crate::Data, crate::Error>,
+    #[rest] input: Option<String>,
+) -> Result<(), Error> {
+    let input = input.unwrap_or_default();
+    let code = match rust_blocks(&input).first() {
+        Some(block) => block.code.to_string(),
+        None => {
+            ctx.msg
+                .reply(ctx.discord, "Put your code in a code block after !run")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let program = Program::from(code);
+    let submission = Submission::from_program(&program);
+    let source = Source {
+        message: ctx.msg,
+        block: 0,
+        react: true,
+    };
+    run_and_reply(ctx.into(), &program, submission, Some(source)).await?;
+    Ok(())
+}
+
+/// The message a run was started from, its reply is threaded to it
+struct Source<'a> {
+    message: &'a Message,
+    /// Which of the rust code blocks of the message is run
+    block: usize,
+    /// Whether the message gets a reaction showing how the run went
+    react: bool,
+}
+
+/// First line of the reply to a run, depending on its place in the queue
+fn status_line(author: &User, position: usize) -> String {
+    match position {
+        0 => format!("Running {}'s code...", author.mention()),
+        position => format!(
+            "{}'s code is queued, position {}",
+            author.mention(),
+            position
+        ),
+    }
+}
+
+/// Lets a reply to a run ping its `author` at most, whatever the code or its
+/// output mention stays silent
+fn allowed_mentions<'a>(
+    mentions: &'a mut CreateAllowedMentions,
+    author: Option<&User>,
+) -> &'a mut CreateAllowedMentions {
+    mentions
+        .empty_parse()
+        .empty_roles()
+        .users(author.map(|author| author.id))
+        .replied_user(false)
+}
+
+/// Queues a run, posts a placeholder reply in the channel and keeps it updated
+/// until the run is over. Runs from a `source` message are remembered, so
+/// editing the message runs it again
+///
+/// Returns how the run ended
+async fn run_and_reply(
+    ctx: Context<'_>,
+    runnable: &(impl Runnable + Sync),
+    submission: Submission,
+    source: Option<Source<'_>>,
+) -> Result<ExecutionOutcome, Error> {
+    // Runs go through the scheduler so a busy channel can't start dozens of
+    // containers at once
+    let ticket = ctx.data().scheduler.enqueue(ctx.author().id.0)?;
 
-            // TODO: better response classification
-            // in the original rustbot we used reactions to indicate successful or failed compilation
-            // or timeouts. With discord's command framework, it's a little more tricky.
-            // For now we just use a canned response for everything, in the future it would be nice to add more
-            // detailed responses for each type of response.
-            reply(ctx, raw_code, Some(stdout), Some(stderr)).await?;
+    // The reply is posted right away and edited as the run progresses
+    let mut message = ctx
+        .channel_id()
+        .send_message(ctx.discord(), |m| {
+            m.allowed_mentions(|am| allowed_mentions(am, Some(ctx.author())));
+            if let Some(source) = &source {
+                m.reference_message(source.message);
+            }
+            m.content(status_line(ctx.author(), ticket.position()))
+        })
+        .await?;
+    let (reply_channel, reply) = (message.channel_id, message.id);
+    let track = |reaction| {
+        if let Some(source) = &source {
+            let mut tracked = TrackedReply::new(
+                reply_channel,
+                reply,
+                ctx.author().clone(),
+                source.block,
+                submission.code.clone(),
+                source.react,
+            );
+            tracked.reaction = reaction;
+            ctx.data().replies.track(source.message.id, tracked);
         }
+    };
+    track(None);
+
+    let outcome = match run_in_message(
+        ctx.discord(),
+        ctx.author(),
+        &mut message,
+        ticket,
+        runnable,
+        &submission,
+    )
+    .await
+    {
+        Ok(outcome) => outcome,
         Err(error) => {
-            // TODO: find out ways this can blow up
-            //println!("TIMEOUT on {}'s code", interaction.);
-            match error.kind() {
-                ErrorKind::TimedOut => {
-                    // Took too long to run, complain to user
-                    //msg.react(&ctx, CROSS_MARK_EMOJI).await?;
-                    //msg.react(&ctx, CLOCK_EMOJI).await?;
-                    reply(
-                        ctx,
-                        raw_code,
-                        None,
-                        Some("Your program took too long to run.".to_owned()),
-                    )
-                    .await?;
-                }
-                _ => {
-                    println!("Error: {:?}", error);
+            // The placeholder has nothing left to show, what went wrong is told
+            // to the user on its own
+            if let Err(e) = message.delete(ctx.discord()).await {
+                println!("Error deleting the reply of a failed run: {:?}", e);
+            }
+            if let Some(source) = &source {
+                ctx.data().replies.remove(source.message.id);
+            }
+            return Err(error);
+        }
+    };
+
+    // Like the original RustBot, the result shows up on the message itself too
+    if let Some(source) = source.as_ref().filter(|source| source.react) {
+        source.message.react(ctx.discord(), outcome.emoji()).await?;
+        track(Some(outcome.emoji()));
+    }
+    Ok(outcome)
+}
+
+/// Waits for the turn of a run, then runs it while showing its output in
+/// `message`
+async fn run_in_message(
+    discord: &serenity::client::Context,
+    author: &User,
+    message: &mut Message,
+    ticket: Ticket,
+    runnable: &(impl Runnable + Sync),
+    submission: &Submission,
+) -> Result<ExecutionOutcome, Error> {
+    let queued = ticket.position() > 0;
+    let _permit = ticket.wait().await;
+    if queued {
+        message
+            .edit(discord, |m| {
+                m.allowed_mentions(|am| allowed_mentions(am, Some(author)));
+                m.content(status_line(author, 0))
+            })
+            .await?;
+    }
+
+    // This leverages the runnable trait we created for executing programs, the
+    // output is streamed into the reply while the program runs
+    let (live_sender, live_receiver) = watch::channel(LiveOutput::default());
+    // An eval only has its value to show, dropping the sender ends the stream
+    let live_sender = (!submission.compact).then_some(live_sender);
+    let (run_result, stream_result) = tokio::join!(
+        runnable.run(live_sender),
+        stream_output(discord, message, submission, live_receiver),
+    );
+    if let Err(e) = stream_result {
+        println!("Error streaming output: {:?}", e);
+    }
+
+    let result = run_result.map_err(Error::run)?;
+    // What programs print is their author's business, the log only gets sizes
+    println!(
+        "Run for {}: {:?}, {} bytes of stdout, {} bytes of stderr",
+        author.tag(),
+        result.outcome,
+        result.stdout.len(),
+        result.stderr.len()
+    );
+
+    // Programs can write anything, invalid UTF-8 included
+    let hex_dump = configuration::HEX_DUMP_BINARY_OUTPUT.value();
+    let stdout = render::decode_output(&result.stdout, hex_dump);
+    let (diagnostics, stderr) = split_diagnostics(&result.stderr, result.build_output, hex_dump);
+
+    // The outcome decides the title and colour of the embed, so a compile error,
+    // a panic or a timeout can be told apart at a glance
+    reply(
+        discord,
+        author,
+        message,
+        submission,
+        result.outcome,
+        result.timings,
+        diagnostics,
+        Some(stdout),
+        Some(stderr),
+    )
+    .await?;
+    Ok(result.outcome)
+}
+
+/// Keeps replies in step with the messages they were run from, editing a
+/// message runs it again and deleting it deletes the reply
+pub async fn on_event(
+    discord: &serenity::client::Context,
+    event: &poise::Event<'_>,
+    data: &crate::Data,
+) -> Result<(), Error> {
+    match event {
+        poise::Event::MessageUpdate { event, .. } => {
+            // Updates without content are embeds being resolved and the like
+            if let Some(content) = &event.content {
+                if let Some(tracked) = data.replies.get(event.id) {
+                    rerun(discord, data, event.channel_id, event.id, content, tracked).await?;
                 }
             }
         }
+        poise::Event::MessageDelete {
+            deleted_message_id, ..
+        } => delete_reply(discord, data, *deleted_message_id).await?,
+        poise::Event::MessageDeleteBulk {
+            multiple_deleted_messages_ids,
+            ..
+        } => {
+            for id in multiple_deleted_messages_ids {
+                delete_reply(discord, data, *id).await?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Runs an edited message again, reusing the reply of its previous run
+async fn rerun(
+    discord: &serenity::client::Context,
+    data: &crate::Data,
+    channel: ChannelId,
+    source: MessageId,
+    content: &str,
+    mut tracked: TrackedReply,
+) -> Result<(), Error> {
+    let blocks = rust_blocks(content);
+    let block = blocks.get(tracked.block).or_else(|| blocks.first());
+    if block.map(|block| block.code) == Some(tracked.code.as_str()) {
+        return Ok(());
+    }
+
+    let mut message = tracked.channel.message(discord, tracked.reply).await?;
+    let code = match block {
+        Some(block) => block.code.to_string(),
+        None => {
+            message
+                .edit(discord, |m| {
+                    m.allowed_mentions(|am| allowed_mentions(am, None));
+                    m.content("The message no longer has a rust code block.")
+                        .set_embeds(Vec::new())
+                        .components(|c| c)
+                })
+                .await?;
+            tracked.code.clear();
+            data.replies.track(source, tracked);
+            return Ok(());
+        }
+    };
+
+    let ticket = match data.scheduler.enqueue(tracked.author.id.0) {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            message
+                .edit(discord, |m| {
+                    m.allowed_mentions(|am| allowed_mentions(am, None));
+                    m.content(e.to_string())
+                        .set_embeds(Vec::new())
+                        .components(|c| c)
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+    message
+        .edit(discord, |m| {
+            m.allowed_mentions(|am| allowed_mentions(am, Some(&tracked.author)));
+            m.content(status_line(&tracked.author, ticket.position()))
+        })
+        .await?;
+
+    let program = Program::from(code);
+    let submission = Submission::from_program(&program);
+    let outcome = match run_in_message(
+        discord,
+        &tracked.author,
+        &mut message,
+        ticket,
+        &program,
+        &submission,
+    )
+    .await
+    {
+        Ok(outcome) => outcome,
+        // Nobody is waiting on an interaction, so the reply says what went wrong
+        Err(error) => {
+            println!("Error re-running {}: {:?}", source, error);
+            message
+                .edit(discord, |m| {
+                    m.allowed_mentions(|am| allowed_mentions(am, None));
+                    m.content(render::message_content(&error.to_string()))
+                        .set_embeds(Vec::new())
+                        .components(|c| c)
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+
+    tracked.code = program.code.clone();
+    // Swap the reaction of the previous run for this one
+    if tracked.react {
+        if let Some(previous) = tracked.reaction {
+            channel
+                .delete_reaction(discord, source, None, previous)
+                .await?;
+        }
+        channel
+            .create_reaction(discord, source, outcome.emoji())
+            .await?;
+        tracked.reaction = Some(outcome.emoji());
     }
+    data.replies.track(source, tracked);
+    Ok(())
+}
 
+/// Deletes the reply of a message that was deleted
+async fn delete_reply(
+    discord: &serenity::client::Context,
+    data: &crate::Data,
+    source: MessageId,
+) -> Result<(), Error> {
+    if let Some(tracked) = data.replies.remove(source) {
+        tracked
+            .channel
+            .delete_message(discord, tracked.reply)
+            .await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::model::id::UserId;
+
+    #[test]
+    fn splits_arguments() {
+        assert_eq!(split_arguments("  a b\tc "), ["a", "b", "c"]);
+        assert_eq!(
+            split_arguments("--name \"Ferris the crab\" 'it''s' \"\""),
+            ["--name", "Ferris the crab", "its", ""]
+        );
+        assert!(split_arguments("   ").is_empty());
+    }
+
+    #[test]
+    fn long_output_is_attached() {
+        assert!(output_attachment("hi\n").is_none());
+        let output: String = (1..=500).map(|i| format!("line {}\n", i)).collect();
+        assert_eq!(
+            output_attachment(&output),
+            Some(output.clone().into_bytes())
+        );
+
+        let submission = Submission::new(
+            "fn main() {}".to_string(),
+            Channel::default(),
+            Edition::default(),
+            Mode::default(),
+        );
+        let fields = output_fields(
+            &submission,
+            &Diagnostics::default(),
+            Some(output),
+            Some(String::new()),
+            0,
+        );
+        assert_eq!(fields.len(), 2);
+        assert!(fields[1].1.starts_with("```\nline 1\nline 2\n"));
+        assert!(fields[1].1.contains(" of 500 lines omitted ...]"));
+        assert!(fields[1].1.chars().count() <= render::FIELD_VALUE_LIMIT);
+    }
+
+    #[test]
+    fn programs_cannot_fake_diagnostics() {
+        let diagnostic = r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"main.rs","line_start":1,"line_end":1,"column_start":1,"column_end":2,"is_primary":true,"text":[],"label":null}],"children":[],"rendered":"error[E0308]: mismatched types\n"}"#;
+        let stderr = format!("{}\n", diagnostic);
+        let (diagnostics, remaining) = split_diagnostics(stderr.as_bytes(), stderr.len(), false);
+        assert_eq!(diagnostics.error_codes(), ["E0308"]);
+        assert_eq!(remaining, "");
+
+        // The same line from the program stays its output
+        let (diagnostics, remaining) = split_diagnostics(stderr.as_bytes(), 0, false);
+        assert!(diagnostics.diagnostics.is_empty());
+        assert_eq!(remaining, stderr);
+    }
+
+    #[test]
+    fn evals_show_only_the_value() {
+        let diagnostics = Diagnostics::default();
+        let success = eval_result("1 + 1", ExecutionOutcome::Success, &diagnostics, "2\n", "");
+        assert_eq!(success, "```rs\n1 + 1\n= 2\n```");
+        let panic = eval_result(
+            "None::<u8>.unwrap()",
+            ExecutionOutcome::NonZeroExit(101),
+            &diagnostics,
+            "",
+            "thread 'main' panicked\n",
+        );
+        assert_eq!(
+            panic,
+            "```rs\nNone::<u8>.unwrap()\nthread 'main' panicked\n```"
+        );
+    }
+
+    #[test]
+    fn replies_only_ping_the_author() {
+        let mut author = User::default();
+        author.id = UserId(42);
+        let mut mentions = CreateAllowedMentions::default();
+        allowed_mentions(&mut mentions, Some(&author));
+        // No @everyone, @here, roles or users other than the author
+        assert_eq!(mentions.0["parse"].as_array().map(Vec::len), Some(0));
+        assert_eq!(mentions.0["roles"].as_array().map(Vec::len), Some(0));
+        let users = mentions.0["users"].as_array().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].as_str(), Some("42"));
+        assert_eq!(mentions.0["replied_user"].as_bool(), Some(false));
+
+        let mut mentions = CreateAllowedMentions::default();
+        allowed_mentions(&mut mentions, None);
+        assert_eq!(mentions.0["users"].as_array().map(Vec::len), Some(0));
+    }
+}
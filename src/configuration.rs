@@ -1,9 +1,16 @@
 use crate::model::configurable::*;
+use crate::model::runtime::Runtime;
+
+/// Selects the backend used to run code, available values: podman,docker,local
+pub const CONTAINER_RUNTIME: &ConfigurableItem<Runtime> = &ConfigurableItem {
+    environment_variable: "CONTAINER_RUNTIME",
+    default_value: Runtime::Podman,
+};
 
 /// Sets the container image to pull
 pub const CONTAINER_IMAGE: &ConfigurableItem<&str> = &ConfigurableItem {
     environment_variable: "CONTAINER_IMAGE",
-    default_value: "ghcr.io/theconner/rustbot-runner:latest",
+    default_value: "ghcr.io/summer-of-rust/ferris-bot/ferris-bot-runner:latest",
 };
 
 /// Sets the maximum amount of virtual CPUs available to the child container
@@ -33,6 +40,61 @@ pub const CONTAINER_MAX_RUNTIME: &ConfigurableItem<u64> = &ConfigurableItem {
     default_value: 5000,
 };
 
+/// Largest program in bytes that the bot accepts, anything bigger is rejected
+/// before a container is started
+pub const MAX_PROGRAM_SIZE: &ConfigurableItem<u64> = &ConfigurableItem {
+    environment_variable: "MAX_PROGRAM_SIZE",
+    default_value: 65536,
+};
+
+/// Most bytes captured from each of stdout and stderr, a run that writes more
+/// is killed
+pub const MAX_OUTPUT_SIZE: &ConfigurableItem<u64> = &ConfigurableItem {
+    environment_variable: "MAX_OUTPUT_SIZE",
+    default_value: 65536,
+};
+
+/// Most bytes of stdout and stderr attached to a reply when they are too long
+/// for its embed, anything past it is cut off
+pub const MAX_ATTACHMENT_SIZE: &ConfigurableItem<u64> = &ConfigurableItem {
+    environment_variable: "MAX_ATTACHMENT_SIZE",
+    default_value: 1048576,
+};
+
+/// Shows output that looks like binary data as a hex dump instead of text with
+/// its invalid UTF-8 replaced, available values: false,true
+pub const HEX_DUMP_BINARY_OUTPUT: &ConfigurableItem<bool> = &ConfigurableItem {
+    environment_variable: "HEX_DUMP_BINARY_OUTPUT",
+    default_value: true,
+};
+
+/// How many runs can execute at the same time, further runs are queued
+pub const MAX_CONCURRENT_RUNS: &ConfigurableItem<u64> = &ConfigurableItem {
+    environment_variable: "MAX_CONCURRENT_RUNS",
+    default_value: 4,
+};
+
+/// How many runs a single user can have queued or running at the same time
+pub const MAX_RUNS_PER_USER: &ConfigurableItem<u64> = &ConfigurableItem {
+    environment_variable: "MAX_RUNS_PER_USER",
+    default_value: 2,
+};
+
+/// Comma separated list of extra rustc flags users may pass to /run, an entry
+/// ending in * allows any flag starting with what comes before it
+pub const RUSTC_FLAGS_ALLOWLIST: &ConfigurableItem<&str> = &ConfigurableItem {
+    environment_variable: "RUSTC_FLAGS_ALLOWLIST",
+    default_value: "-C overflow-checks=*,-C debug-assertions=*,-C opt-level=*,-C target-cpu=*,-C codegen-units=*,-C panic=*",
+};
+
+/// Comma separated list of third party crates programs can use, as
+/// `name=version` optionally followed by `:feature+feature`. These have to be
+/// vendored in the runner image, see runner/crates/Cargo.toml
+pub const CRATE_ALLOWLIST: &ConfigurableItem<&str> = &ConfigurableItem {
+    environment_variable: "CRATE_ALLOWLIST",
+    default_value: "rand=0.8,serde=1:derive,serde_json=1,itertools=0.13,regex=1,tokio=1:full",
+};
+
 /// Tells the bot if it's running in a container this will influence flags it
 /// chooses for child containers available values: false,true
 pub const IS_RUNNING_IN_CONTAINER: &ConfigurableItem<bool> = &ConfigurableItem {
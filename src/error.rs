@@ -0,0 +1,186 @@
+use std::fmt;
+use std::io;
+
+use poise::serenity_prelude as serenity;
+use serenity::model::interactions::{Interaction, InteractionResponseType};
+
+use crate::model::explain::ErrorCode;
+use crate::model::outcome::ExecutionOutcome;
+use crate::model::render;
+use crate::model::scheduler::TooManyRunsError;
+use crate::Data;
+
+/// Everything that can make a command or event handler fail
+///
+/// The `Display` of an error is what the user gets to see, the `Debug` is what
+/// ends up in the log
+#[derive(Debug)]
+pub enum Error {
+    /// The program was refused before it ran, e.g. for being too large
+    Rejected(String),
+    /// The container runtime, or the trampoline for the local runtime, isn't
+    /// installed on the host
+    RuntimeMissing(io::Error),
+    /// The sandbox could not be started, or broke down while running
+    Sandbox(io::Error),
+    /// The user already has as many runs going as they are allowed
+    TooManyRuns(TooManyRunsError),
+    /// There is no quiz question with this number
+    UnknownQuestion { number: i64, questions: usize },
+    /// `rustc --explain` didn't get to print anything
+    Explanation(ErrorCode, ExecutionOutcome),
+    /// A request to Discord failed
+    Discord(serenity::Error),
+    /// Reading a file of the bot failed
+    Io(io::Error),
+}
+
+impl Error {
+    /// Sorts out why running something in the sandbox failed
+    pub fn run(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::InvalidInput => Error::Rejected(error.to_string()),
+            io::ErrorKind::NotFound => Error::RuntimeMissing(error),
+            _ => Error::Sandbox(error),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Rejected(reason) => f.write_str(reason),
+            Error::RuntimeMissing(_) => f.write_str(
+                "Code can't be run right now, the bot's container runtime is not installed.",
+            ),
+            Error::Sandbox(_) => f.write_str(
+                "Something went wrong with the sandbox your code runs in, please try again later.",
+            ),
+            Error::TooManyRuns(e) => write!(f, "{}", e),
+            Error::UnknownQuestion { number, questions } => write!(
+                f,
+                "There is no question {}, pick one from 1 to {}.",
+                number, questions
+            ),
+            Error::Explanation(code, outcome) => {
+                write!(f, "Could not get the explanation of {}: {}", code, outcome)
+            }
+            Error::Discord(_) => f.write_str(
+                "Discord didn't accept what the bot tried to do, please try again later.",
+            ),
+            Error::Io(_) => f.write_str("Something went wrong on the bot's side."),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::RuntimeMissing(e) | Error::Sandbox(e) | Error::Io(e) => Some(e),
+            Error::TooManyRuns(e) => Some(e),
+            Error::Discord(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serenity::Error> for Error {
+    fn from(error: serenity::Error) -> Self {
+        Error::Discord(error)
+    }
+}
+
+impl From<TooManyRunsError> for Error {
+    fn from(error: TooManyRunsError) -> Self {
+        Error::TooManyRuns(error)
+    }
+}
+
+/// Logs every error and tells whoever ran into it what happened, only them if
+/// Discord allows it
+pub async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
+    // Rejections can quote what the user sent
+    let explain = |error: &Error| render::message_content(&error.to_string());
+    match error {
+        poise::FrameworkError::Command { error, ctx } => {
+            println!(
+                "Error in {}{} for {}: {:?}",
+                ctx.prefix(),
+                ctx.command().name,
+                ctx.author().tag(),
+                error
+            );
+            let reply = ctx
+                .send(|m| m.content(explain(&error)).ephemeral(true))
+                .await;
+            if let Err(e) = reply {
+                println!("Error telling the user about it: {:?}", e);
+            }
+        }
+        poise::FrameworkError::Listener {
+            error, event, ctx, ..
+        } => {
+            println!("Error handling {} event: {:?}", event.name(), error);
+            // Buttons are the only events someone is waiting on an answer for
+            if let poise::Event::InteractionCreate {
+                interaction: Interaction::MessageComponent(component),
+            } = event
+            {
+                let response = component
+                    .create_interaction_response(&ctx, |r| {
+                        r.kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|d| {
+                                d.content(explain(&error)).ephemeral(true)
+                            })
+                    })
+                    .await;
+                // The button was already acknowledged before things went wrong
+                let response = match response {
+                    Ok(()) => Ok(()),
+                    Err(_) => component
+                        .create_followup_message(&ctx, |m| {
+                            m.content(explain(&error)).ephemeral(true)
+                        })
+                        .await
+                        .map(|_| ()),
+                };
+                if let Err(e) = response {
+                    println!("Error telling the user about it: {:?}", e);
+                }
+            }
+        }
+        error => {
+            if let Err(e) = poise::builtins::on_error(error).await {
+                println!("Error handling an error: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_out_run_errors() {
+        let rejected = io::Error::new(io::ErrorKind::InvalidInput, "Your program is too large.");
+        assert!(
+            matches!(Error::run(rejected), Error::Rejected(reason) if reason == "Your program is too large.")
+        );
+        let missing = io::Error::new(io::ErrorKind::NotFound, "No such file or directory");
+        assert!(matches!(Error::run(missing), Error::RuntimeMissing(_)));
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "Broken pipe");
+        assert!(matches!(Error::run(broken), Error::Sandbox(_)));
+    }
+
+    #[test]
+    fn users_see_what_went_wrong_not_how() {
+        let error = Error::run(io::Error::new(io::ErrorKind::NotFound, "podman: not found"));
+        assert!(!error.to_string().contains("podman"));
+        assert!(format!("{:?}", error).contains("podman: not found"));
+        assert_eq!(
+            Error::from(TooManyRunsError(2)).to_string(),
+            "You already have 2 runs in progress, wait for them to finish."
+        );
+    }
+}
@@ -1,16 +1,35 @@
 use dotenv::dotenv;
 use poise::serenity_prelude as serenity;
+use std::time::Duration;
 
 mod commands;
 mod configuration;
+mod error;
 mod model;
-use crate::commands::{quiz, run};
+use crate::commands::{explain, quiz, run};
+use crate::model::configurable::ConfigurableValue;
 use crate::model::container::{get_container_settings, ContainerActions};
+use crate::model::explain::Explanations;
+use crate::model::replies::ReplyTracker;
+use crate::model::scheduler::Scheduler;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub use crate::error::Error;
 pub type Context<'a> = poise::Context<'a, Data, Error>;
 // User data, which is stored and accessible in all command invocations
-pub struct Data {}
+pub struct Data {
+    /// Decides when each run gets to start
+    pub scheduler: Scheduler,
+    /// Replies to runs started from a message, for re-running edited messages
+    pub replies: ReplyTracker,
+    /// Explanations of rustc error codes fetched so far
+    pub explanations: Explanations,
+}
+
+/// How many runs started from a message are remembered for re-running
+const TRACKED_REPLIES: usize = 1000;
+
+/// How long a message can be edited to run it again
+const TRACKED_REPLY_AGE: Duration = Duration::from_secs(60 * 60);
 
 /// Registers or unregisters application commands in this guild or globally
 #[poise::command(prefix_command, hide_in_help)]
@@ -25,16 +44,51 @@ async fn main() {
     dotenv().ok();
 
     // Before anything, pull the latest container image for running rust code
-    // We will use RustBot's runner image for this
-    // https://github.com/TheConner/RustBot/pkgs/container/rustbot-runner
-    if let Err(e) = get_container_settings().pull_image() {
+    // The image is built from the runner directory of this repository
+    let container_settings = get_container_settings();
+    if !container_settings.runtime.enforces_limits() {
+        println!(
+            "Warning: the {} runtime does not isolate or limit the code it runs",
+            container_settings.runtime
+        );
+    }
+    if let Err(e) = container_settings.pull_image() {
         println!("Error pulling image: {:?}", e);
     };
 
+    // Containers from a previous instance that crashed or was killed mid-run can
+    // still be alive, get rid of them before accepting new runs
+    match container_settings.cleanup_orphans() {
+        Ok(0) => {}
+        Ok(removed) => println!("Removed {} orphaned containers", removed),
+        Err(e) => println!("Error removing orphaned containers: {:?}", e),
+    }
+
     println!("Starting up...");
     let framework = poise::Framework::build()
         .options(poise::FrameworkOptions {
-            commands: vec![register(), quiz::quiz(), run::run()],
+            commands: vec![
+                register(),
+                quiz::quiz(),
+                run::run(),
+                run::run_project(),
+                run::run_message(),
+                run::eval(),
+                explain::explain(),
+            ],
+            listener: |discord, event, _framework, data| {
+                Box::pin(async move {
+                    run::on_event(discord, event, data).await?;
+                    explain::on_event(discord, event, data).await
+                })
+            },
+            on_error: |error| Box::pin(error::on_error(error)),
+            // Replies can echo code and output, nothing in them should ping
+            allowed_mentions: Some({
+                let mut mentions = serenity::CreateAllowedMentions::default();
+                mentions.empty_parse().empty_roles().empty_users();
+                mentions
+            }),
             prefix_options: poise::PrefixFrameworkOptions {
                 prefix: Some("!".into()),
                 ..Default::default()
@@ -45,7 +99,20 @@ async fn main() {
         .intents(
             serenity::GatewayIntents::non_privileged() | serenity::GatewayIntents::MESSAGE_CONTENT,
         )
-        .user_data_setup(move |_ctx, _ready, _framework| Box::pin(async move { Ok(Data {}) }));
+        .user_data_setup(move |_ctx, _ready, _framework| {
+            Box::pin(async move {
+                Ok(Data {
+                    scheduler: Scheduler::new(
+                        configuration::MAX_CONCURRENT_RUNS.value() as usize,
+                        configuration::MAX_RUNS_PER_USER.value() as usize,
+                    ),
+                    replies: ReplyTracker::new(TRACKED_REPLIES, TRACKED_REPLY_AGE),
+                    explanations: Explanations::new(),
+                })
+            })
+        });
 
-    framework.run().await.unwrap();
+    if let Err(e) = framework.run().await {
+        println!("Error running the bot: {:?}", e);
+    }
 }
@@ -0,0 +1,118 @@
+/// A fenced code block found in a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeBlock<'a> {
+    /// Whatever follows the opening fence, empty if there is none
+    pub language: &'a str,
+    pub code: &'a str,
+}
+
+impl CodeBlock<'_> {
+    /// Whether the block is tagged as rust
+    pub fn is_rust(&self) -> bool {
+        self.language.eq_ignore_ascii_case("rust") || self.language.eq_ignore_ascii_case("rs")
+    }
+}
+
+/// Every ```fenced``` block in `content`, in order
+///
+/// Like Discord, a language is only recognised when the opening fence is
+/// followed by a single word and a newline, ```fn main() {}``` is all code.
+pub fn code_blocks(content: &str) -> Vec<CodeBlock<'_>> {
+    let mut blocks = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("```") {
+        let after_fence = &rest[start + 3..];
+        let end = match after_fence.find("```") {
+            Some(end) => end,
+            None => break,
+        };
+        let inner = &after_fence[..end];
+        rest = &after_fence[end + 3..];
+
+        let block = match inner.split_once('\n') {
+            Some((language, code))
+                if !language.trim().is_empty()
+                    && !language.trim().contains(char::is_whitespace) =>
+            {
+                CodeBlock {
+                    language: language.trim(),
+                    code,
+                }
+            }
+            Some((language, code)) if language.trim().is_empty() => {
+                CodeBlock { language: "", code }
+            }
+            _ => CodeBlock {
+                language: "",
+                code: inner,
+            },
+        };
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// The code blocks of `content` worth running, the ones tagged rust or, when
+/// there are none of those, the untagged ones
+pub fn rust_blocks(content: &str) -> Vec<CodeBlock<'_>> {
+    let blocks = code_blocks(content);
+    if blocks.iter().any(CodeBlock::is_rust) {
+        blocks.into_iter().filter(CodeBlock::is_rust).collect()
+    } else {
+        blocks
+            .into_iter()
+            .filter(|block| block.language.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_blocks() {
+        let content = "look\n```rust\nfn main() {}\n```\nand ```py\nprint()``` or ```x```";
+        assert_eq!(
+            code_blocks(content),
+            [
+                CodeBlock {
+                    language: "rust",
+                    code: "fn main() {}\n",
+                },
+                CodeBlock {
+                    language: "py",
+                    code: "print()",
+                },
+                CodeBlock {
+                    language: "",
+                    code: "x",
+                },
+            ]
+        );
+        assert!(code_blocks("```rust\nunterminated").is_empty());
+    }
+
+    #[test]
+    fn first_line_with_code_is_not_a_language() {
+        assert_eq!(
+            code_blocks("```fn main() {\n}```")[0],
+            CodeBlock {
+                language: "",
+                code: "fn main() {\n}",
+            }
+        );
+    }
+
+    #[test]
+    fn prefers_tagged_blocks() {
+        let content = "```\nuntagged\n``` ```rs\ntagged\n``` ```toml\nx = 1\n```";
+        let blocks = rust_blocks(content);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "tagged\n");
+
+        let blocks = rust_blocks("```\nuntagged\n``` ```toml\nx = 1\n```");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].code, "untagged\n");
+    }
+}
@@ -1,3 +1,5 @@
+use crate::model::runtime::Runtime;
+
 /// Struct for holding a Configuration item of type T
 /// This is a generic struct that can be used to hold any type of configuration item that can be pulled out of environment variables
 pub struct ConfigurableItem<T> {
@@ -34,3 +36,12 @@ impl ConfigurableValue<bool> for ConfigurableItem<bool> {
             .unwrap_or(self.default_value)
     }
 }
+
+impl ConfigurableValue<Runtime> for ConfigurableItem<Runtime> {
+    fn value(&self) -> Runtime {
+        std::env::var(self.environment_variable)
+            .unwrap_or_else(|_| self.default_value.to_string())
+            .parse()
+            .unwrap_or(self.default_value)
+    }
+}
@@ -1,107 +1,560 @@
 use crate::configuration;
 use crate::model::configurable::ConfigurableValue;
+use crate::model::crates::{parse_allowlist, AllowedCrate};
+use crate::model::runtime::Runtime;
 use std::io;
 use std::io::Error;
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Label attached to every container started by the bot, used to find orphans
+pub const RUN_LABEL: &str = "ferris-bot.run";
+
+/// The only variables of the bot's environment a local run gets, anything else,
+/// like DISCORD_TOKEN, would be there for the program to print
+const LOCAL_ENVIRONMENT: &[&str] = &["PATH", "HOME", "RUSTUP_HOME", "CARGO_HOME"];
+
+/// Disambiguates runs started within the same millisecond
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a container name that is unique to this run
+pub fn unique_container_name() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!(
+        "ferris-bot-{}-{}-{}",
+        std::process::id(),
+        millis,
+        RUN_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
 
 /// Settings for our container
 #[derive(Clone)]
 pub struct ContainerSettings {
+    pub runtime: Runtime,
     pub cpu: String,
     pub memory: String,
+    // BUG: see generate_runtime_flags, swap is not passed to the runtime yet
+    #[allow(dead_code)]
     pub swap: String,
     pub image: String,
     pub max_runtime: u64,
+    pub max_program_size: u64,
+    pub max_output_size: u64,
     pub network: String,
+    /// Third party crates vendored in the image
+    pub crates: Vec<AllowedCrate>,
+}
+
+/// A single invocation of the sandbox, kept as a program and its argument
+/// vector so that nothing ever goes through a shell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxCommand {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl SandboxCommand {
+    pub fn new(program: impl Into<String>) -> Self {
+        SandboxCommand {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// The full argument vector, including the program itself
+    #[cfg(test)]
+    pub fn argv(&self) -> Vec<&str> {
+        std::iter::once(self.program.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Turns this into a `std::process::Command` with piped input and output
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
 }
 
 pub trait ContainerActions {
-    fn generate_runtime_flags(&self, is_container: bool) -> String;
+    fn generate_runtime_flags(&self, is_container: bool) -> Vec<String>;
+    fn build_command(
+        &self,
+        is_container: bool,
+        name: &str,
+        entrypoint: &str,
+        payload: Vec<String>,
+    ) -> SandboxCommand;
     fn pull_image(&self) -> Result<(), Error>;
-    fn invoke_command(&self, command: String) -> io::Result<std::process::Child>;
+    fn invoke_command(
+        &self,
+        name: &str,
+        entrypoint: &str,
+        payload: Vec<String>,
+    ) -> io::Result<tokio::process::Child>;
+    fn kill_container(&self, name: &str) -> Result<(), Error>;
+    fn cleanup_orphans(&self) -> Result<usize, Error>;
 }
 
 impl ContainerActions for ContainerSettings {
-    /// Turns a ContainerSettings instance into a string of CLI args for Podman or Docker
+    /// Turns a ContainerSettings instance into CLI args for the configured runtime
     /// is_container: describes if we are running rustbot in a container
-    fn generate_runtime_flags(&self, is_container: bool) -> String {
+    fn generate_runtime_flags(&self, is_container: bool) -> Vec<String> {
         // BUG: when swap is included, we get a OCI runtime error as memory+swap is greater than configured memory
         // fix and re-add swap constraint
-        // NOTE: podman-in-podman requires cgroups to set resources, which isn't available within nested containers
-        // so, admins will have to limit the resources on the outer container themselves
-        if is_container {
-            String::from("")
-        } else {
-            format!(
-                "--cpus={} --memory={} --network={}",
-                self.cpu, self.memory, self.network
-            )
+        match self.runtime {
+            // NOTE: podman-in-podman requires cgroups to set resources, which isn't available within nested containers
+            // so, admins will have to limit the resources on the outer container themselves
+            Runtime::Podman if is_container => Vec::new(),
+            // Docker containers are created by the daemon on the host, so the limits
+            // apply even when the bot itself is containerised
+            Runtime::Podman | Runtime::Docker => vec![
+                format!("--cpus={}", self.cpu),
+                format!("--memory={}", self.memory),
+                format!("--network={}", self.network),
+            ],
+            // The local runtime has no way of enforcing any of these
+            Runtime::Local => Vec::new(),
+        }
+    }
+
+    /// Builds the invocation that runs `entrypoint` with `payload` inside the sandbox
+    /// name: unique name of the container, see `unique_container_name`
+    fn build_command(
+        &self,
+        is_container: bool,
+        name: &str,
+        entrypoint: &str,
+        payload: Vec<String>,
+    ) -> SandboxCommand {
+        match self.runtime.binary() {
+            Some(binary) => {
+                let mut command = SandboxCommand::new(binary);
+                command
+                    .args(["run", "--rm", "--interactive"])
+                    .arg(format!("--name={}", name))
+                    .arg(format!("--label={}", RUN_LABEL))
+                    .args(self.generate_runtime_flags(is_container))
+                    .arg(&self.image)
+                    .arg(entrypoint)
+                    .args(payload);
+                command
+            }
+            None => {
+                let mut command = SandboxCommand::new(entrypoint);
+                command.args(payload);
+                command
+            }
         }
     }
 
     /// Pulls a container image from a registry
     fn pull_image(&self) -> Result<(), Error> {
-        let output = Command::new("podman")
-            .arg("pull")
-            .arg(&self.image)
-            .status()
-            .expect("failed to execute process");
+        let binary = match self.runtime.binary() {
+            Some(binary) => binary,
+            // Nothing to pull, the trampoline is expected to be installed on the host
+            None => return Ok(()),
+        };
 
-        let status = output.code().expect("No output code");
+        let status = Command::new(binary).arg("pull").arg(&self.image).status()?;
 
-        if status == 0 {
+        if status.success() {
             Ok(())
         } else {
-            Result::Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Could not pull container image, got error code {}", status),
-            ))
+            // Without a code, pull was killed by a signal
+            Result::Err(io::Error::other(format!(
+                "Could not pull container image, {}",
+                status
+            )))
         }
     }
 
-    fn invoke_command(&self, command: String) -> io::Result<std::process::Child> {
-        let container_command = format!(
-            "podman run --rm {} {} {}",
-            self.generate_runtime_flags(configuration::IS_RUNNING_IN_CONTAINER.value()),
-            self.image,
-            command
-        );
+    fn invoke_command(
+        &self,
+        name: &str,
+        entrypoint: &str,
+        payload: Vec<String>,
+    ) -> io::Result<tokio::process::Child> {
+        let mut command = self
+            .build_command(
+                configuration::IS_RUNNING_IN_CONTAINER.value(),
+                name,
+                entrypoint,
+                payload,
+            )
+            .to_command();
+        if self.runtime == Runtime::Local {
+            isolate_local(&mut command);
+        }
 
-        //println!("{}", container_command);
-
-        // Because std::command does not give me the ability to override / modify
-        // how arguments are escaped I have to do some stupid hack to make this
-        // work. For example, if I wanted to run
-        // podman run rustbot:latest ls -al
-        // this would be impossible if I did
-        //
-        //  std::process::Command::new("podman")
-        //    .args(["run", "rustbot:latest", "ls -al"])
-        //    .output()
-        //    .expect("failed to invoke container");
-        //
-        // As the ls -al would be quoted, and the container would try to execute
-        // `ls -al` which would fail. The alternative is to seperate "ls", "-al"
-        // which would also fail as the container would run `ls` then `-al`
-        // ... what a stupid design
-        // So instead of embracing the safety this API gives you, i'm just invoking
-        // a shell with a payload I deem as safe
-        Command::new("sh")
-            .args(["-c", container_command.as_str()])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
+        // Dropping the child kills the client process, the container itself is
+        // taken care of by ContainerGuard
+        tokio::process::Command::from(command)
+            .kill_on_drop(true)
             .spawn()
     }
+
+    /// Kills and removes a container, then checks that it is really gone
+    fn kill_container(&self, name: &str) -> Result<(), Error> {
+        let binary = match self.runtime.binary() {
+            Some(binary) => binary,
+            // Local runs have no container, ContainerGuard kills their process
+            // group instead
+            None => return Ok(()),
+        };
+
+        // The container may already be on its way out, so failures here are fine
+        // as long as the container does not exist afterwards
+        Command::new(binary)
+            .args(["kill", name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        Command::new(binary)
+            .args(["rm", "-f", name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+
+        let still_exists = Command::new(binary)
+            .args(["container", "inspect", name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?
+            .success();
+
+        if still_exists {
+            Err(io::Error::other(format!(
+                "Container {} is still present after being killed",
+                name
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Removes containers left behind by previous instances of the bot, returns
+    /// how many were removed
+    fn cleanup_orphans(&self) -> Result<usize, Error> {
+        let binary = match self.runtime.binary() {
+            Some(binary) => binary,
+            None => return Ok(0),
+        };
+
+        let output = Command::new(binary)
+            .args(["ps", "-a", "--format", "{{.Names}}"])
+            .arg(format!("--filter=label={}", RUN_LABEL))
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Could not list containers, got {}",
+                output.status
+            )));
+        }
+
+        let names = String::from_utf8_lossy(&output.stdout);
+        let mut removed = 0;
+        for name in names.lines().map(str::trim).filter(|n| !n.is_empty()) {
+            self.kill_container(name)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+/// Keeps a local run away from the bot's secrets, and puts it in a process
+/// group of its own so rustc, cargo and the program can all be killed at once
+fn isolate_local(command: &mut Command) {
+    command.env_clear();
+    for key in LOCAL_ENVIRONMENT {
+        if let Some(value) = std::env::var_os(key) {
+            command.env(key, value);
+        }
+    }
+    command.process_group(0);
+}
+
+/// Kills every process of the group led by the local run `id`
+fn kill_process_group(id: u32) -> Result<(), Error> {
+    // The group may be gone already, which is what we are after anyway
+    Command::new("kill")
+        .args(["-KILL", "--", &format!("-{}", id)])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(())
+}
+
+/// Kills its container when dropped unless the run finished on its own, this
+/// makes sure timeouts, errors and cancelled runs never leave a container behind
+pub struct ContainerGuard {
+    settings: ContainerSettings,
+    name: String,
+    /// The process started for the run, a local run is killed through its group
+    process: Option<u32>,
+    armed: bool,
+}
+
+impl ContainerGuard {
+    pub fn new(settings: ContainerSettings, name: String, process: Option<u32>) -> Self {
+        ContainerGuard {
+            settings,
+            name,
+            process,
+            armed: true,
+        }
+    }
+
+    /// The container exited by itself and was removed by `--rm`. A local run
+    /// has nothing like it, whatever it left running is still killed on drop
+    pub fn disarm(mut self) {
+        self.armed = self.settings.runtime == Runtime::Local;
+    }
+
+    /// Kills the container right away, reporting whether it is gone
+    pub async fn kill(mut self) -> Result<(), Error> {
+        self.armed = false;
+        let kill = self.killer();
+        tokio::task::spawn_blocking(kill)
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Kills the container, or the process group of a local run, when called
+    fn killer(&self) -> impl FnOnce() -> Result<(), Error> {
+        let settings = self.settings.clone();
+        let name = self.name.clone();
+        let process = self.process;
+        move || match (settings.runtime, process) {
+            (Runtime::Local, Some(id)) => kill_process_group(id),
+            _ => settings.kill_container(&name),
+        }
+    }
+}
+
+impl Drop for ContainerGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        let killer = self.killer();
+        let name = std::mem::take(&mut self.name);
+        let kill = move || {
+            if let Err(e) = killer() {
+                println!("Error killing container {}: {:?}", name, e);
+            }
+        };
+
+        // Talking to the runtime blocks, keep that off the async workers when we can
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(kill);
+            }
+            Err(_) => kill(),
+        }
+    }
 }
 
 /// Gets the default container settings
 pub fn get_container_settings() -> ContainerSettings {
     ContainerSettings {
+        runtime: (*configuration::CONTAINER_RUNTIME).value(),
         cpu: (*configuration::CONTAINER_CPU).value(),
         image: (*configuration::CONTAINER_IMAGE).value(),
         memory: (*configuration::CONTAINER_MEMORY).value(),
         swap: (*configuration::CONTAINER_SWAP).value(),
         max_runtime: (*configuration::CONTAINER_MAX_RUNTIME).value(),
+        max_program_size: (*configuration::MAX_PROGRAM_SIZE).value(),
+        max_output_size: (*configuration::MAX_OUTPUT_SIZE).value(),
         network: (*configuration::CONTAINER_NETWORK).value(),
+        crates: parse_allowlist(&(*configuration::CRATE_ALLOWLIST).value()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(runtime: Runtime) -> ContainerSettings {
+        ContainerSettings {
+            runtime,
+            cpu: String::from("0.5"),
+            memory: String::from("100m"),
+            swap: String::from("5m"),
+            image: String::from("ghcr.io/theconner/rustbot-runner:latest"),
+            max_runtime: 5000,
+            max_program_size: 65536,
+            max_output_size: 65536,
+            network: String::from("none"),
+            crates: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn podman_argv() {
+        let command =
+            settings(Runtime::Podman).build_command(false, "run-1", "trampoline", Vec::new());
+        assert_eq!(
+            command.argv(),
+            [
+                "podman",
+                "run",
+                "--rm",
+                "--interactive",
+                "--name=run-1",
+                "--label=ferris-bot.run",
+                "--cpus=0.5",
+                "--memory=100m",
+                "--network=none",
+                "ghcr.io/theconner/rustbot-runner:latest",
+                "trampoline",
+            ]
+        );
+    }
+
+    #[test]
+    fn podman_in_container_argv_has_no_limits() {
+        let command =
+            settings(Runtime::Podman).build_command(true, "run-1", "trampoline", Vec::new());
+        assert_eq!(
+            command.argv(),
+            [
+                "podman",
+                "run",
+                "--rm",
+                "--interactive",
+                "--name=run-1",
+                "--label=ferris-bot.run",
+                "ghcr.io/theconner/rustbot-runner:latest",
+                "trampoline",
+            ]
+        );
+    }
+
+    #[test]
+    fn docker_argv_keeps_limits_in_container() {
+        let command =
+            settings(Runtime::Docker).build_command(true, "run-1", "trampoline", Vec::new());
+        assert_eq!(
+            command.argv(),
+            [
+                "docker",
+                "run",
+                "--rm",
+                "--interactive",
+                "--name=run-1",
+                "--label=ferris-bot.run",
+                "--cpus=0.5",
+                "--memory=100m",
+                "--network=none",
+                "ghcr.io/theconner/rustbot-runner:latest",
+                "trampoline",
+            ]
+        );
+    }
+
+    #[test]
+    fn unique_container_names() {
+        assert_ne!(unique_container_name(), unique_container_name());
+    }
+
+    #[test]
+    fn local_argv() {
+        let command =
+            settings(Runtime::Local).build_command(false, "run-1", "trampoline", Vec::new());
+        assert_eq!(command.argv(), ["trampoline"]);
+    }
+
+    #[test]
+    fn local_runs_only_get_a_few_variables() {
+        let mut command = Command::new("env");
+        isolate_local(&mut command);
+        let output = command.output().unwrap();
+        let environment = String::from_utf8(output.stdout).unwrap();
+        assert!(environment.lines().any(|line| line.starts_with("PATH=")));
+        for line in environment.lines() {
+            let key = line.split('=').next().unwrap();
+            assert!(LOCAL_ENVIRONMENT.contains(&key), "{} leaked", key);
+        }
+    }
+
+    #[test]
+    fn local_runs_are_killed_with_everything_they_started() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 60 & echo $!; wait"]);
+        isolate_local(&mut command);
+        let mut run = command.stdout(Stdio::piped()).spawn().unwrap();
+        let mut started = String::new();
+        io::BufRead::read_line(
+            &mut io::BufReader::new(run.stdout.take().unwrap()),
+            &mut started,
+        )
+        .unwrap();
+
+        kill_process_group(run.id()).unwrap();
+        assert!(run.wait().unwrap().code().is_none());
+        // The background sleep went too, it is not even a zombie once reaped
+        let sleep = format!("/proc/{}/stat", started.trim());
+        let gone = (0..50).any(|_| {
+            let state = std::fs::read_to_string(&sleep).unwrap_or_default();
+            if state.is_empty() || state.contains(") Z ") {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            false
+        });
+        assert!(gone);
+    }
+
+    #[test]
+    fn settings_are_never_shell_interpreted() {
+        let mut settings = settings(Runtime::Podman);
+        settings.image = String::from("img; rm -rf /");
+        settings.cpu = String::from("$(reboot)");
+        let command = settings.build_command(false, "run-1", "trampoline", vec!["a b".into()]);
+        assert_eq!(
+            command.argv(),
+            [
+                "podman",
+                "run",
+                "--rm",
+                "--interactive",
+                "--name=run-1",
+                "--label=ferris-bot.run",
+                "--cpus=$(reboot)",
+                "--memory=100m",
+                "--network=none",
+                "img; rm -rf /",
+                "trampoline",
+                "a b",
+            ]
+        );
     }
 }
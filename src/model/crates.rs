@@ -0,0 +1,155 @@
+use crate::model::eval::blank_comments_and_literals;
+
+/// A third party crate the runner image has vendored and programs may use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedCrate {
+    pub name: String,
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+impl AllowedCrate {
+    /// Parses an allowlist entry, `name=version` optionally followed by
+    /// `:feature+feature`
+    pub fn parse(entry: &str) -> Option<Self> {
+        let (name, rest) = entry.trim().split_once('=')?;
+        let (version, features) = match rest.split_once(':') {
+            Some((version, features)) => (version, features.split('+').collect()),
+            None => (rest, Vec::new()),
+        };
+
+        let valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.^~*".contains(c))
+        };
+        if !valid(name) || !valid(version) || !features.iter().all(|f| valid(f)) {
+            return None;
+        }
+
+        Some(AllowedCrate {
+            name: name.to_string(),
+            version: version.to_string(),
+            features: features.into_iter().map(String::from).collect(),
+        })
+    }
+
+    /// How the crate is referred to in code, dashes become underscores
+    pub fn ident(&self) -> String {
+        self.name.replace('-', "_")
+    }
+
+    /// Data of the `crate` frame that has the trampoline add this crate to the
+    /// manifest, `<name> <version> <feature,feature>`
+    pub fn frame(&self) -> String {
+        format!("{} {} {}", self.name, self.version, self.features.join(","))
+    }
+
+    /// Whether `code` refers to this crate through a path or `extern crate`,
+    /// mentions in comments and strings don't count
+    pub fn is_used_by(&self, code: &str) -> bool {
+        let code = blank_comments_and_literals(code);
+        let code = code.as_str();
+        let ident = self.ident();
+        let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+
+        code.match_indices(&ident).any(|(start, _)| {
+            let before = &code[..start];
+            let after = &code[start + ident.len()..];
+
+            // `foo::rand::thing`, `self::rand` and `crate::rand` are modules
+            // called rand, not the crate, but a leading `::rand::thing` is
+            let before = before.strip_suffix("::").unwrap_or(before);
+            if before.ends_with(is_ident_char) {
+                return false;
+            }
+            if after.starts_with("::") {
+                return true;
+            }
+
+            // extern crate rand;
+            before.trim_end().ends_with("extern crate") && !after.starts_with(is_ident_char)
+        })
+    }
+}
+
+/// Parses a comma separated allowlist, ignoring malformed entries
+pub fn parse_allowlist(allowlist: &str) -> Vec<AllowedCrate> {
+    allowlist
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = AllowedCrate::parse(entry);
+            if parsed.is_none() {
+                println!("Ignoring malformed crate allowlist entry {:?}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// The allowlisted crates that `code` uses
+pub fn detect_crates(code: &str, allowlist: &[AllowedCrate]) -> Vec<AllowedCrate> {
+    allowlist
+        .iter()
+        .filter(|allowed| allowed.is_used_by(code))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> Vec<AllowedCrate> {
+        parse_allowlist("rand=0.8, serde=1:derive, tokio=1:full+macros, serde-json=1")
+    }
+
+    #[test]
+    fn parses_entries() {
+        let allowlist = allowlist();
+        assert_eq!(allowlist.len(), 4);
+        assert_eq!(
+            allowlist[2],
+            AllowedCrate {
+                name: String::from("tokio"),
+                version: String::from("1"),
+                features: vec![String::from("full"), String::from("macros")],
+            }
+        );
+        assert!(parse_allowlist("rand=\"0.8\"\nevil=1").is_empty());
+    }
+
+    #[test]
+    fn detects_used_crates() {
+        let code = r#"
+            use rand::Rng;
+            #[tokio::main]
+            async fn main() {
+                let v = serde_json::json!({});
+            }
+        "#;
+        let names: Vec<_> = detect_crates(code, &allowlist())
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(names, ["rand", "tokio", "serde-json"]);
+    }
+
+    #[test]
+    fn ignores_lookalikes() {
+        let code = "mod rand { pub fn x() {} } fn main() { self::rand::x(); crate::rand::x(); let operand = 1; }";
+        assert!(detect_crates(code, &allowlist()).is_empty());
+        let code =
+            "// use rand::Rng;\n/* tokio::main */\nfn main() { println!(\"serde::Serialize\"); }";
+        assert!(detect_crates(code, &allowlist()).is_empty());
+        assert_eq!(
+            detect_crates("extern crate rand;\nfn main() {}", &allowlist()).len(),
+            1
+        );
+        assert_eq!(
+            detect_crates("fn main() { ::serde::x() }", &allowlist()).len(),
+            1
+        );
+    }
+}
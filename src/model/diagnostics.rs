@@ -0,0 +1,291 @@
+use serde::Deserialize;
+
+/// A compiler diagnostic, as rustc writes them with `--error-format=json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub code: Option<DiagnosticCode>,
+    /// error, warning, note, help or failure-note
+    pub level: String,
+    pub spans: Vec<Span>,
+    /// The diagnostic as rustc would have shown it, with colours
+    pub rendered: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiagnosticCode {
+    /// e.g. E0308, or the name of a lint
+    pub code: String,
+}
+
+/// A piece of code a diagnostic points at
+#[derive(Debug, Clone, Deserialize)]
+pub struct Span {
+    pub file_name: String,
+    pub line_start: usize,
+    pub column_start: usize,
+    pub is_primary: bool,
+    pub label: Option<String>,
+    /// The lines of code spanned
+    pub text: Vec<SpanLine>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpanLine {
+    pub text: String,
+    /// Columns of the spanned part of the line, counting from 1
+    pub highlight_start: usize,
+    pub highlight_end: usize,
+}
+
+/// How cargo wraps diagnostics with `--message-format=json`
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Diagnostic,
+}
+
+impl Diagnostic {
+    /// Parses a line of rustc or cargo JSON output
+    fn parse(line: &str) -> Option<Self> {
+        if !line.starts_with('{') {
+            return None;
+        }
+        if let Ok(diagnostic) = serde_json::from_str::<Diagnostic>(line) {
+            return Some(diagnostic);
+        }
+        serde_json::from_str::<CargoMessage>(line)
+            .ok()
+            .filter(|message| message.reason == "compiler-message")
+            .map(|message| message.message)
+    }
+
+    /// Whether this is an error or warning about the code, rather than a note
+    /// like "aborting due to 2 previous errors"
+    fn is_about_code(&self) -> bool {
+        !self.spans.is_empty()
+    }
+
+    fn primary_span(&self) -> Option<&Span> {
+        self.spans.iter().find(|span| span.is_primary)
+    }
+}
+
+/// The diagnostics of a build
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    /// Splits the JSON diagnostics out of what the build wrote to stderr,
+    /// returning the rest. The program's own stderr never goes through here
+    pub fn extract(stderr: &str) -> (Diagnostics, String) {
+        let mut diagnostics = Vec::new();
+        let mut remaining = String::with_capacity(stderr.len());
+        for line in stderr.split_inclusive('\n') {
+            match Diagnostic::parse(line.trim_end()) {
+                Some(diagnostic) => diagnostics.push(diagnostic),
+                None => remaining.push_str(line),
+            }
+        }
+        (Diagnostics { diagnostics }, remaining)
+    }
+
+    fn count(&self, level: &str) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.level == level && diagnostic.is_about_code())
+            .count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.count("error")
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.count("warning")
+    }
+
+    /// The error codes of the errors, each once and in order
+    pub fn error_codes(&self) -> Vec<&str> {
+        let mut codes = Vec::new();
+        for diagnostic in &self.diagnostics {
+            if let Some(code) = &diagnostic.code {
+                if code.code.starts_with('E') && !codes.contains(&code.code.as_str()) {
+                    codes.push(code.code.as_str());
+                }
+            }
+        }
+        codes
+    }
+
+    /// The first error about the code in colour, like [`Diagnostics::summary`]
+    /// shows it. `None` if there is none
+    pub fn first_error(&self) -> Option<String> {
+        self.diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.level == "error" && diagnostic.is_about_code())
+            .map(describe)
+    }
+
+    /// Everything rustc would have printed, with colours
+    pub fn rendered(&self) -> String {
+        self.diagnostics
+            .iter()
+            .filter_map(|diagnostic| diagnostic.rendered.as_deref())
+            .collect()
+    }
+
+    /// A short version of the diagnostics in colour: how many errors and
+    /// warnings there are, their error codes and where the first one is.
+    /// `None` if there is nothing to summarise
+    pub fn summary(&self) -> Option<String> {
+        let (errors, warnings) = (self.errors(), self.warnings());
+        let plural = |count: usize, what: &str| {
+            format!("{} {}{}", count, what, if count == 1 { "" } else { "s" })
+        };
+        let mut counts = Vec::new();
+        if errors > 0 {
+            counts.push(format!("\x1b[1;31m{}\x1b[0m", plural(errors, "error")));
+        }
+        if warnings > 0 {
+            counts.push(format!("\x1b[1;33m{}\x1b[0m", plural(warnings, "warning")));
+        }
+        if counts.is_empty() {
+            return None;
+        }
+
+        let mut summary = counts.join(", ");
+        let codes = self.error_codes();
+        if !codes.is_empty() {
+            summary.push_str(&format!(" ({})", codes.join(", ")));
+        }
+        summary.push('\n');
+
+        // Errors first, the first warning only matters when nothing failed
+        let first = ["error", "warning"].iter().find_map(|level| {
+            self.diagnostics
+                .iter()
+                .find(|diagnostic| diagnostic.level == *level && diagnostic.is_about_code())
+        });
+        if let Some(diagnostic) = first {
+            summary.push_str(&describe(diagnostic));
+        }
+        Some(summary)
+    }
+}
+
+/// A diagnostic with its primary span and the line it is on, like rustc
+/// shows it without the notes and secondary spans
+fn describe(diagnostic: &Diagnostic) -> String {
+    let colour = if diagnostic.level == "error" { 31 } else { 33 };
+    // Like rustc, error codes are shown but lint names aren't
+    let code = diagnostic
+        .code
+        .as_ref()
+        .filter(|code| code.code.starts_with('E'))
+        .map(|code| format!("[{}]", code.code))
+        .unwrap_or_default();
+    let mut description = format!(
+        "\x1b[1;{}m{}{}\x1b[0m\x1b[1m: {}\x1b[0m\n",
+        colour, diagnostic.level, code, diagnostic.message
+    );
+
+    let span = match diagnostic.primary_span() {
+        Some(span) => span,
+        None => return description,
+    };
+    let number = span.line_start.to_string();
+    let gutter = " ".repeat(number.len());
+    description.push_str(&format!(
+        "{}\x1b[1;34m--> \x1b[0m{}:{}:{}\n",
+        gutter, span.file_name, span.line_start, span.column_start
+    ));
+    if let Some(line) = span.text.first() {
+        let start = line.highlight_start.max(1);
+        let width = line.highlight_end.saturating_sub(start).max(1);
+        description.push_str(&format!(
+            "\x1b[1;34m{} |\x1b[0m {}\n",
+            number,
+            line.text.trim_end()
+        ));
+        let label = span
+            .label
+            .as_ref()
+            .map(|label| format!(" {}", label))
+            .unwrap_or_default();
+        description.push_str(&format!(
+            "\x1b[1;34m{} |\x1b[0m {}\x1b[1;{}m{}{}\x1b[0m\n",
+            gutter,
+            " ".repeat(start - 1),
+            colour,
+            "^".repeat(width),
+            label
+        ));
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::render::strip_ansi;
+
+    const MISMATCHED: &str = r#"{"$message_type":"diagnostic","message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"main.rs","byte_start":49,"byte_end":52,"line_start":3,"line_end":3,"column_start":18,"column_end":21,"is_primary":true,"text":[{"text":"    let x: i32 = \"a\";","highlight_start":18,"highlight_end":21}],"label":"expected `i32`, found `&str`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0308]: mismatched types\n"}"#;
+    const UNUSED: &str = r#"{"reason":"compiler-message","package_id":"main 0.1.0","message":{"$message_type":"diagnostic","message":"unused variable: `y`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[{"file_name":"src/main.rs","line_start":2,"line_end":2,"column_start":9,"column_end":10,"is_primary":true,"text":[{"text":"    let y = 1;","highlight_start":9,"highlight_end":10}],"label":null}],"children":[],"rendered":"warning: unused variable: `y`\n"}}"#;
+    const ABORTING: &str = r#"{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[],"rendered":"error: aborting due to 1 previous error\n"}"#;
+
+    #[test]
+    fn extracts_rustc_and_cargo_diagnostics() {
+        let stderr = format!(
+            "trampoline: hello\n{}\n{}\n{}\nthread 'main' panicked\n",
+            UNUSED, MISMATCHED, ABORTING
+        );
+        let (diagnostics, remaining) = Diagnostics::extract(&stderr);
+        assert_eq!(remaining, "trampoline: hello\nthread 'main' panicked\n");
+        assert_eq!(diagnostics.diagnostics.len(), 3);
+        assert_eq!(diagnostics.errors(), 1);
+        assert_eq!(diagnostics.warnings(), 1);
+        assert_eq!(diagnostics.error_codes(), ["E0308"]);
+        assert_eq!(
+            diagnostics.rendered(),
+            "warning: unused variable: `y`\nerror[E0308]: mismatched types\nerror: aborting due to 1 previous error\n"
+        );
+    }
+
+    #[test]
+    fn summarises_the_first_error() {
+        let stderr = format!("{}\n{}\n{}\n", UNUSED, MISMATCHED, ABORTING);
+        let summary = Diagnostics::extract(&stderr).0.summary().unwrap();
+        assert_eq!(
+            strip_ansi(&summary),
+            concat!(
+                "1 error, 1 warning (E0308)\n",
+                "error[E0308]: mismatched types\n",
+                " --> main.rs:3:18\n",
+                "3 |     let x: i32 = \"a\";\n",
+                "  |                  ^^^ expected `i32`, found `&str`\n",
+            )
+        );
+
+        // An eval shows the same error without the counts
+        let error = Diagnostics::extract(&stderr).0.first_error().unwrap();
+        assert!(error.starts_with("\x1b[1;31merror[E0308]"));
+        assert!(summary.ends_with(&error));
+
+        let summary = Diagnostics::extract(UNUSED).0.summary().unwrap();
+        assert!(strip_ansi(&summary).contains("\nwarning: unused variable: `y`\n"));
+        assert!(strip_ansi(&summary).ends_with("2 |     let y = 1;\n  |         ^\n"));
+    }
+
+    #[test]
+    fn nothing_to_summarise() {
+        let (diagnostics, remaining) = Diagnostics::extract("{not json}\nplain\n");
+        assert!(diagnostics.diagnostics.is_empty());
+        assert_eq!(remaining, "{not json}\nplain\n");
+        assert!(diagnostics.summary().is_none());
+        let (diagnostics, _) = Diagnostics::extract(ABORTING);
+        assert!(diagnostics.summary().is_none());
+    }
+}
@@ -0,0 +1,264 @@
+/// Keywords starting a statement or item that ends with a `}` and no `;`
+const BLOCK_STATEMENTS: &[&str] = &[
+    "fn",
+    "struct",
+    "enum",
+    "union",
+    "impl",
+    "trait",
+    "mod",
+    "macro_rules!",
+    "pub",
+    "for",
+    "while",
+    "async",
+    "unsafe fn",
+    "extern",
+];
+
+/// Turns `let v = vec![1, 2]; v.len()` into a program printing the `{:?}` of
+/// its final expression, anything before it runs as is
+pub fn wrap_expression(input: &str) -> String {
+    let (statements, expression) = split_expression(input);
+    let mut program = String::from("#![allow(unused)]\nfn main() {\n");
+    program.push_str(statements);
+    program.push('\n');
+    // The expression is evaluated in the arguments, so it can borrow from the
+    // statements. It gets its own lines in case it ends in a comment
+    if let Some(expression) = expression {
+        program.push_str("println!(\"{:?}\", (\n");
+        program.push_str(expression);
+        program.push_str("\n));\n");
+    }
+    program.push_str("}\n");
+    program
+}
+
+/// Splits code into the statements and the final expression, `None` if it
+/// ends in a statement
+fn split_expression(input: &str) -> (&str, Option<&str>) {
+    let mut split = top_level(input)
+        .filter(|&(_, c)| c == ';')
+        .last()
+        .map_or(0, |(end, _)| end);
+
+    // Items and loops are statements without a `;`, they go with the rest
+    loop {
+        let tail = skip_comments(&input[split..]);
+        let is_block_statement = tail.starts_with("#[")
+            || BLOCK_STATEMENTS.iter().any(|keyword| {
+                tail.strip_prefix(keyword).is_some_and(|rest| {
+                    keyword.ends_with('!')
+                        || rest.starts_with(|c: char| c.is_whitespace() || c == '(')
+                })
+            });
+        if !is_block_statement {
+            break;
+        }
+        let offset = input.len() - tail.len();
+        match top_level(tail).find(|&(_, c)| c == '}') {
+            Some((end, _)) => split = offset + end,
+            None => break,
+        }
+    }
+
+    let expression = input[split..].trim();
+    let has_expression = !skip_comments(expression).is_empty();
+    (&input[..split], has_expression.then_some(expression))
+}
+
+/// Strips whitespace and comments from the start of `code`
+fn skip_comments(mut code: &str) -> &str {
+    loop {
+        code = code.trim_start();
+        if code.starts_with("//") {
+            code = code.find('\n').map_or("", |newline| &code[newline..]);
+        } else if code.starts_with("/*") {
+            // Nested comments are rare enough here to not bother
+            code = code.find("*/").map_or("", |end| &code[end + 2..]);
+        } else {
+            return code;
+        }
+    }
+}
+
+/// Offsets just past every `;` and `}` of `code` that are not nested in
+/// brackets, strings, character literals or comments
+fn top_level(code: &str) -> impl Iterator<Item = (usize, char)> {
+    let mut ends = Vec::new();
+    let mut depth = 0usize;
+    for (i, byte) in blank_comments_and_literals(code).bytes().enumerate() {
+        match byte {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' => depth = depth.saturating_sub(1),
+            b'}' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    ends.push((i + 1, '}'));
+                }
+            }
+            b';' if depth == 0 => ends.push((i + 1, ';')),
+            _ => {}
+        }
+    }
+    ends.into_iter()
+}
+
+/// `code` with its comments, strings and character literals replaced by
+/// spaces, so what is left can be searched without running into them. Offsets
+/// into the result are offsets into `code`
+pub fn blank_comments_and_literals(code: &str) -> String {
+    let bytes = code.as_bytes();
+    let mut blanked = bytes.to_vec();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                // Block comments nest
+                let mut nesting = 0;
+                while i < bytes.len() {
+                    if bytes[i..].starts_with(b"/*") {
+                        nesting += 1;
+                        i += 2;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        nesting -= 1;
+                        i += 2;
+                        if nesting == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            b'r' if is_raw_string(&bytes[i..]) => {
+                let hashes = bytes[i + 1..].iter().take_while(|&&b| b == b'#').count();
+                let mut closing = vec![b'"'];
+                closing.extend(std::iter::repeat_n(b'#', hashes));
+                i += 2 + hashes;
+                while i < bytes.len() && !bytes[i..].starts_with(&closing) {
+                    i += 1;
+                }
+                i += closing.len();
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            b'\'' => {
+                // 'a' and '\n' are characters, 'a without a closing quote is a lifetime
+                let end = if bytes.get(i + 1) == Some(&b'\\') {
+                    bytes[i + 2..]
+                        .iter()
+                        .position(|&b| b == b'\'')
+                        .map(|p| i + 2 + p)
+                } else {
+                    let len = code[i + 1..].chars().next().map_or(0, char::len_utf8);
+                    (bytes.get(i + 1 + len) == Some(&b'\'')).then_some(i + 1 + len)
+                };
+                match end {
+                    Some(end) => i = end + 1,
+                    None => {
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        let end = i.min(bytes.len());
+        blanked[start..end].fill(b' ');
+    }
+    // Only whole characters were blanked, each blanked region starts and ends
+    // at an ASCII byte or the end of the code
+    String::from_utf8(blanked).expect("blanking keeps the code valid UTF-8")
+}
+
+/// Whether `code` starts with `r"` or `r#`, and not an identifier ending in r
+fn is_raw_string(code: &[u8]) -> bool {
+    let hashes = code[1..].iter().take_while(|&&b| b == b'#').count();
+    code.get(1 + hashes) == Some(&b'"')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_expression() {
+        assert_eq!(
+            split_expression("vec![1, 2, 3].iter().rev().collect::<Vec<_>>()"),
+            ("", Some("vec![1, 2, 3].iter().rev().collect::<Vec<_>>()"))
+        );
+    }
+
+    #[test]
+    fn statements_before_the_expression() {
+        assert_eq!(
+            split_expression("let v = vec![1; 3];\nlet w = { 1; 2 };\nv.len() + w"),
+            (
+                "let v = vec![1; 3];\nlet w = { 1; 2 };",
+                Some("v.len() + w")
+            )
+        );
+        assert_eq!(split_expression("let x = 1;"), ("let x = 1;", None));
+    }
+
+    #[test]
+    fn items_are_statements() {
+        assert_eq!(
+            split_expression(
+                "#[derive(Debug)]\nstruct P { x: i32 }\nfn p() -> P { P { x: 1 } }\np()"
+            ),
+            (
+                "#[derive(Debug)]\nstruct P { x: i32 }\nfn p() -> P { P { x: 1 } }",
+                Some("p()")
+            )
+        );
+        assert_eq!(
+            split_expression("if true { 1 } else { 2 }"),
+            ("", Some("if true { 1 } else { 2 }"))
+        );
+    }
+
+    #[test]
+    fn ignores_strings_chars_and_comments() {
+        let code = "let s = \";}\"; let r = r#\"\";\"#; let c = ';'; // ;\nfn f<'a>(x: &'a str) -> &'a str { x } /* ; */ f(s)";
+        let (statements, expression) = split_expression(code);
+        assert!(statements.ends_with("{ x }"));
+        assert_eq!(expression, Some("/* ; */ f(s)"));
+        assert_eq!(split_expression("1; // done"), ("1;", None));
+    }
+
+    #[test]
+    fn blanks_comments_and_literals() {
+        let code = "let s = \"é;\"; // a;\nlet c = '}'; /* b /* c */ */ f::<'a>(r#\"\"#)";
+        let blanked = blank_comments_and_literals(code);
+        assert_eq!(blanked.len(), code.len());
+        assert_eq!(
+            blanked,
+            "let s =      ;      \nlet c =    ;                 f::<'a>(     )"
+        );
+    }
+
+    #[test]
+    fn wraps_in_main() {
+        assert_eq!(
+            wrap_expression("let x = 2; x * 3"),
+            "#![allow(unused)]\nfn main() {\nlet x = 2;\nprintln!(\"{:?}\", (\nx * 3\n));\n}\n"
+        );
+    }
+}
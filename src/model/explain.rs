@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::model::payload::Payload;
+use crate::model::render;
+
+/// Most characters on a page of an explanation, embed descriptions can hold
+/// more but long pages are hard to read on a phone
+pub const PAGE_SIZE: usize = 1800;
+
+/// A rustc error code like E0382
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ErrorCode(String);
+
+impl ErrorCode {
+    /// Accepts `E0382`, `e382` and `0382`, `None` for anything that can't be
+    /// an error code
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let digits = input
+            .strip_prefix(|c| c == 'E' || c == 'e')
+            .unwrap_or(input);
+        if digits.is_empty() || digits.len() > 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let number: u16 = digits.parse().ok()?;
+        Some(ErrorCode(format!("E{:04}", number)))
+    }
+
+    /// Builds the payload that has the trampoline print the explanation
+    /// instead of compiling anything
+    pub fn payload(&self) -> Payload {
+        let mut payload = Payload::new("");
+        payload.frame("explain", self.0.as_str());
+        payload
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Explanations fetched so far, they only change with the toolchain so each
+/// one is fetched once
+#[derive(Default)]
+pub struct Explanations {
+    /// `None` for codes rustc doesn't know
+    pages: Mutex<HashMap<ErrorCode, Option<Arc<Vec<String>>>>>,
+}
+
+impl Explanations {
+    pub fn new() -> Self {
+        Explanations::default()
+    }
+
+    /// The pages of an explanation, the outer `None` if it hasn't been fetched
+    pub fn get(&self, code: &ErrorCode) -> Option<Option<Arc<Vec<String>>>> {
+        self.pages.lock().unwrap().get(code).cloned()
+    }
+
+    /// Remembers the output of `rustc --explain`, `None` if rustc didn't know
+    /// the code
+    pub fn insert(&self, code: ErrorCode, explanation: Option<&str>) -> Option<Arc<Vec<String>>> {
+        let pages = explanation.map(|explanation| Arc::new(paginate(explanation, PAGE_SIZE)));
+        self.pages.lock().unwrap().insert(code, pages.clone());
+        pages
+    }
+}
+
+/// Splits the markdown of an explanation into pages of at most `page_size`
+/// characters, breaking between paragraphs and code blocks where possible
+pub fn paginate(markdown: &str, page_size: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    for block in blocks(markdown)
+        .into_iter()
+        .flat_map(|block| split_block(block, page_size))
+    {
+        let length = page.chars().count() + block.chars().count() + 2;
+        if !page.is_empty() && length > page_size {
+            pages.push(std::mem::take(&mut page));
+        }
+        if !page.is_empty() {
+            page.push_str("\n\n");
+        }
+        page.push_str(&block);
+    }
+    if !page.is_empty() || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// A paragraph, heading or list item, or a whole code block
+#[derive(Debug, PartialEq, Eq)]
+enum Block {
+    Text(String),
+    Code(Vec<String>),
+}
+
+/// Splits `rustc --explain` output into blocks, undoing its wrapping at 80
+/// columns so Discord can wrap the text to fit the screen instead
+fn blocks(markdown: &str) -> Vec<Block> {
+    let links = link_definitions(markdown);
+    let mut blocks = Vec::new();
+    let mut text: Option<String> = None;
+    let mut code: Option<Vec<String>> = None;
+    for line in markdown.lines() {
+        if code.is_none() && link_definition(line).is_some() {
+            continue;
+        }
+        if let Some(lines) = &mut code {
+            if line.trim_start().starts_with("```") {
+                blocks.push(Block::Code(code.take().unwrap()));
+            } else {
+                lines.push(line.to_string());
+            }
+            continue;
+        }
+
+        let trimmed = line.trim();
+        let starts_block = trimmed.is_empty()
+            || trimmed.starts_with("```")
+            || trimmed.starts_with('#')
+            || trimmed.starts_with("- ")
+            || trimmed.starts_with("* ");
+        if starts_block {
+            blocks.extend(text.take().map(Block::Text));
+        }
+        if trimmed.starts_with("```") {
+            code = Some(Vec::new());
+        } else if let Some(heading) = trimmed.strip_prefix('#') {
+            // Embeds don't show the smaller headings rustc uses
+            let heading = heading.trim_start_matches('#').trim();
+            blocks.push(Block::Text(format!("**{}**", heading)));
+        } else if !trimmed.is_empty() {
+            match &mut text {
+                Some(text) => {
+                    text.push(' ');
+                    text.push_str(trimmed);
+                }
+                None => text = Some(trimmed.to_string()),
+            }
+        }
+    }
+    blocks.extend(text.map(Block::Text));
+    // An unclosed code block still gets closed on its page
+    blocks.extend(code.map(Block::Code));
+    blocks
+        .into_iter()
+        .map(|block| match block {
+            Block::Text(text) => Block::Text(resolve_links(&text, &links)),
+            code => code,
+        })
+        .collect()
+}
+
+/// A `[label]: url` line, which defines where `[text][label]` links go
+fn link_definition(line: &str) -> Option<(&str, &str)> {
+    let (label, url) = line.strip_prefix('[')?.split_once("]: ")?;
+    let url = url.trim();
+    (!url.is_empty() && !url.contains(char::is_whitespace)).then_some((label, url))
+}
+
+fn link_definitions(markdown: &str) -> HashMap<&str, &str> {
+    let mut in_code = false;
+    let mut links = HashMap::new();
+    for line in markdown.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        } else if let Some((label, url)) = link_definition(line).filter(|_| !in_code) {
+            links.insert(label, url);
+        }
+    }
+    links
+}
+
+/// Turns `[text][label]` and `[label]` links into `[text](url)`, which is the
+/// only kind of link embeds show
+fn resolve_links(text: &str, links: &HashMap<&str, &str>) -> String {
+    let mut resolved = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        resolved.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let link = rest[1..].split_once(']').and_then(|(label, after)| {
+            let (reference, length) = match after
+                .strip_prefix('[')
+                .and_then(|after| after.split_once(']'))
+            {
+                Some((reference, _)) => (reference, label.len() + reference.len() + 4),
+                None => (label, label.len() + 2),
+            };
+            Some((label, *links.get(reference)?, length))
+        });
+        match link {
+            Some((label, url, length)) => {
+                resolved.push_str(&format!("[{}]({})", label, url));
+                rest = &rest[length..];
+            }
+            None => {
+                resolved.push('[');
+                rest = &rest[1..];
+            }
+        }
+    }
+    resolved.push_str(rest);
+    resolved
+}
+
+/// Turns a block into markdown, in pieces of at most `page_size` characters if
+/// it is too long for one page
+fn split_block(block: Block, page_size: usize) -> Vec<String> {
+    match block {
+        Block::Text(text) => {
+            let mut pieces = vec![String::new()];
+            for word in text.split(' ') {
+                let piece = pieces.last_mut().unwrap();
+                if !piece.is_empty() && piece.chars().count() + word.chars().count() >= page_size {
+                    pieces.push(String::new());
+                }
+                let piece = pieces.last_mut().unwrap();
+                if !piece.is_empty() {
+                    piece.push(' ');
+                }
+                piece.push_str(&render::truncate(word, page_size));
+            }
+            pieces
+        }
+        Block::Code(lines) => {
+            // The examples are rust unless rustc says otherwise, and each piece
+            // gets its own fences
+            let fences = "```rust\n".len() + "```".len();
+            let budget = page_size.saturating_sub(fences);
+            let mut pieces: Vec<String> = vec![String::new()];
+            for line in lines {
+                let line = render::truncate(&line, budget.saturating_sub(1));
+                let piece = pieces.last_mut().unwrap();
+                if !piece.is_empty() && piece.chars().count() + line.chars().count() >= budget {
+                    pieces.push(String::new());
+                }
+                let piece = pieces.last_mut().unwrap();
+                piece.push_str(&render::escape_fences(&line));
+                piece.push('\n');
+            }
+            pieces
+                .into_iter()
+                .map(|piece| format!("```rust\n{}```", piece))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPLANATION: &str = "A variable was used after its contents have been moved elsewhere.
+
+Erroneous code example:
+
+```
+let x = vec![1];
+let y = x;
+```
+
+Since `Vec` is a type that is not marked `Copy`, the data gets moved out
+of `x` when we set `y`.
+
+#### Note
+
+- first
+- second
+
+See [the book][book] or [rustup] for more, [this] stays as it is.
+
+[book]: https://doc.rust-lang.org/book/
+[rustup]: https://rustup.rs
+";
+
+    #[test]
+    fn parses_error_codes() {
+        for input in ["E0382", "e382", "0382", " 382 "] {
+            assert_eq!(ErrorCode::parse(input).unwrap().to_string(), "E0382");
+        }
+        for input in ["", "E", "E12345", "E03a2", "unused_variables"] {
+            assert_eq!(ErrorCode::parse(input), None);
+        }
+    }
+
+    #[test]
+    fn joins_wrapped_lines() {
+        let pages = paginate(EXPLANATION, PAGE_SIZE);
+        assert_eq!(
+            pages,
+            [concat!(
+                "A variable was used after its contents have been moved elsewhere.\n\n",
+                "Erroneous code example:\n\n",
+                "```rust\nlet x = vec![1];\nlet y = x;\n```\n\n",
+                "Since `Vec` is a type that is not marked `Copy`, the data gets moved out of `x` when we set `y`.\n\n",
+                "**Note**\n\n",
+                "- first\n\n",
+                "- second\n\n",
+                "See [the book](https://doc.rust-lang.org/book/) or [rustup](https://rustup.rs) for more, [this] stays as it is.",
+            )]
+        );
+    }
+
+    #[test]
+    fn breaks_between_blocks() {
+        let pages = paginate(EXPLANATION, 100);
+        assert!(pages.len() > 1);
+        assert!(pages.iter().all(|page| page.chars().count() <= 100));
+        assert!(pages[0].ends_with("\n\nErroneous code example:"));
+        assert_eq!(pages[1], "```rust\nlet x = vec![1];\nlet y = x;\n```");
+        // Every page has closed fences
+        assert!(pages
+            .iter()
+            .all(|page| page.matches("```").count() % 2 == 0));
+    }
+
+    #[test]
+    fn splits_long_code_blocks() {
+        let code: String = (0..50).map(|i| format!("let x{} = {};\n", i, i)).collect();
+        let pages = paginate(&format!("```\n{}```\n", code), 200);
+        assert!(pages.len() > 1);
+        for page in &pages {
+            assert!(page.chars().count() <= 200);
+            assert!(page.starts_with("```rust\n") && page.ends_with("\n```"));
+        }
+        let lines: Vec<&str> = pages
+            .iter()
+            .flat_map(|page| page.lines().filter(|line| !line.starts_with("```")))
+            .collect();
+        assert_eq!(lines.len(), 50);
+    }
+}
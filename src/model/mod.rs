@@ -1,4 +1,17 @@
+pub mod codeblock;
 pub mod configurable;
 pub mod container;
+pub mod crates;
+pub mod diagnostics;
+pub mod eval;
+pub mod explain;
+pub mod outcome;
+pub mod payload;
+pub mod project;
 pub mod question;
+pub mod render;
+pub mod replies;
 pub mod runnable;
+pub mod runtime;
+pub mod scheduler;
+pub mod toolchain;
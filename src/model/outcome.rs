@@ -0,0 +1,257 @@
+use std::fmt;
+use std::time::Duration;
+
+use serenity::utils::Colour;
+
+/// Prefix of the lines the trampoline writes to stderr to talk to the bot
+const MARKER_PREFIX: &[u8] = b"::ferris-bot::";
+
+/// Exit code of a rust program that panicked
+const PANIC_EXIT_CODE: i64 = 101;
+
+const SIGKILL: i64 = 9;
+
+/// Exit code reported by container runtimes when the process was SIGKILLed,
+/// which for us means the OOM killer got to it
+const SIGKILL_EXIT_CODE: i64 = 128 + SIGKILL;
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionOutcome {
+    CompileError,
+    Panicked,
+    NonZeroExit(i64),
+    Timeout,
+    OutOfMemory,
+    OutputLimitExceeded,
+    Success,
+}
+
+/// Where the time of a run went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Time spent in rustc, if the trampoline got far enough to report it
+    pub compile: Option<Duration>,
+    /// Wall clock time of the whole run, as seen by the bot
+    pub total: Duration,
+}
+
+/// Everything we know about a finished run
+#[derive(Debug, Clone)]
+pub struct ExecutionResult {
+    pub outcome: ExecutionOutcome,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// How many bytes at the start of `stderr` were written while building the
+    /// program, the rest is the program's own
+    pub build_output: usize,
+    pub timings: Timings,
+}
+
+/// What the trampoline told us through its markers
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Markers {
+    pub compile_failed: bool,
+    pub compile_time: Option<Duration>,
+    /// How many bytes at the start of the remaining stderr were written before
+    /// the program started, only those can be compiler diagnostics
+    pub build_output: usize,
+}
+
+impl Markers {
+    /// Splits the trampoline markers out of stderr, returning the remaining stderr
+    ///
+    /// Either marker is the last thing the trampoline writes, everything after
+    /// it comes from the program and is kept as is, even if it looks like a marker
+    pub fn extract(stderr: &[u8]) -> (Markers, Vec<u8>) {
+        let mut markers = Markers::default();
+        let mut remaining = Vec::with_capacity(stderr.len());
+        let mut lines = stderr.split_inclusive(|b| *b == b'\n');
+        let mut build_output = None;
+
+        for line in lines.by_ref() {
+            let marker = match line.strip_prefix(MARKER_PREFIX) {
+                Some(marker) => String::from_utf8_lossy(marker),
+                None => {
+                    remaining.extend_from_slice(line);
+                    continue;
+                }
+            };
+
+            let mut parts = marker.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("compile-failed"), _) => {
+                    markers.compile_failed = true;
+                    break;
+                }
+                (Some("compiled"), Some(millis)) => {
+                    markers.compile_time = millis.parse().ok().map(Duration::from_millis);
+                    build_output = Some(remaining.len());
+                    break;
+                }
+                _ => remaining.extend_from_slice(line),
+            }
+        }
+        for line in lines {
+            remaining.extend_from_slice(line);
+        }
+        // Until the program runs, everything is written while building it
+        markers.build_output = build_output.unwrap_or(remaining.len());
+
+        (markers, remaining)
+    }
+}
+
+impl ExecutionOutcome {
+    /// Works out how a run ended from its exit code or signal and the trampoline markers
+    pub fn classify(
+        code: Option<i64>,
+        signal: Option<i32>,
+        markers: &Markers,
+        stderr: &[u8],
+    ) -> Self {
+        if markers.compile_failed {
+            return Self::CompileError;
+        }
+
+        match (code, signal) {
+            (Some(0), _) => Self::Success,
+            (_, Some(signal)) if i64::from(signal) == SIGKILL => Self::OutOfMemory,
+            (Some(SIGKILL_EXIT_CODE), _) => Self::OutOfMemory,
+            (Some(PANIC_EXIT_CODE), _) if contains(stderr, b"panicked at") => Self::Panicked,
+            (Some(code), _) => Self::NonZeroExit(code),
+            // Killed by some other signal, report it the way a shell would
+            (None, Some(signal)) => Self::NonZeroExit(128 + i64::from(signal)),
+            (None, None) => Self::NonZeroExit(-1),
+        }
+    }
+
+    /// Colour of the embed used to show this outcome
+    pub fn colour(&self) -> Colour {
+        match self {
+            Self::Success => Colour::DARK_GREEN,
+            Self::CompileError => Colour::RED,
+            Self::Panicked | Self::NonZeroExit(_) => Colour::ORANGE,
+            Self::Timeout | Self::OutOfMemory | Self::OutputLimitExceeded => Colour::GOLD,
+        }
+    }
+
+    /// Reaction left on the message of a prefix run
+    pub fn emoji(&self) -> char {
+        match self {
+            Self::Success => '\u{2705}',
+            Self::CompileError => '\u{274C}',
+            Self::Panicked | Self::NonZeroExit(_) => '\u{26A0}',
+            Self::Timeout => '\u{23F0}',
+            Self::OutOfMemory | Self::OutputLimitExceeded => '\u{1F6D1}',
+        }
+    }
+}
+
+impl fmt::Display for ExecutionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CompileError => write!(f, "Compilation failed"),
+            Self::Panicked => write!(f, "Program panicked"),
+            Self::NonZeroExit(code) => write!(f, "Program exited with code {}", code),
+            Self::Timeout => write!(f, "Program took too long to run"),
+            Self::OutOfMemory => write!(f, "Program ran out of memory"),
+            Self::OutputLimitExceeded => write!(f, "Program produced too much output"),
+            Self::Success => write!(f, "Program ran successfully"),
+        }
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.compile {
+            Some(compile) => write!(
+                f,
+                "Compiled in {}ms, ran in {}ms",
+                compile.as_millis(),
+                self.total.saturating_sub(compile).as_millis()
+            ),
+            None => write!(f, "Took {}ms", self.total.as_millis()),
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_are_stripped() {
+        let stderr = b"warning: unused variable\n::ferris-bot::compiled 1234\nhello\n";
+        let (markers, remaining) = Markers::extract(stderr);
+        assert_eq!(markers.compile_time, Some(Duration::from_millis(1234)));
+        assert!(!markers.compile_failed);
+        assert_eq!(remaining, b"warning: unused variable\nhello\n");
+        assert_eq!(markers.build_output, "warning: unused variable\n".len());
+    }
+
+    #[test]
+    fn compile_failure_wins() {
+        let (markers, stderr) = Markers::extract(b"error[E0308]\n::ferris-bot::compile-failed\n");
+        assert_eq!(markers.build_output, stderr.len());
+        assert_eq!(
+            ExecutionOutcome::classify(Some(1), None, &markers, &stderr),
+            ExecutionOutcome::CompileError
+        );
+    }
+
+    #[test]
+    fn programs_cannot_fake_markers() {
+        let stderr = b"::ferris-bot::compiled 1234\n::ferris-bot::compile-failed\n::ferris-bot::compiled 1\n";
+        let (markers, remaining) = Markers::extract(stderr);
+        assert_eq!(markers.compile_time, Some(Duration::from_millis(1234)));
+        assert!(!markers.compile_failed);
+        assert_eq!(
+            remaining,
+            b"::ferris-bot::compile-failed\n::ferris-bot::compiled 1\n"
+        );
+        assert_eq!(markers.build_output, 0);
+        assert_eq!(
+            ExecutionOutcome::classify(Some(0), None, &markers, &remaining),
+            ExecutionOutcome::Success
+        );
+    }
+
+    #[test]
+    fn exit_statuses() {
+        let markers = Markers::default();
+        let panic = b"thread 'main' panicked at 'oops', main.rs:1:1\n";
+        assert_eq!(
+            ExecutionOutcome::classify(Some(0), None, &markers, b""),
+            ExecutionOutcome::Success
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(Some(101), None, &markers, panic),
+            ExecutionOutcome::Panicked
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(Some(101), None, &markers, b""),
+            ExecutionOutcome::NonZeroExit(101)
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(Some(137), None, &markers, b""),
+            ExecutionOutcome::OutOfMemory
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(None, Some(9), &markers, b""),
+            ExecutionOutcome::OutOfMemory
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(None, Some(11), &markers, b""),
+            ExecutionOutcome::NonZeroExit(139)
+        );
+        assert_eq!(
+            ExecutionOutcome::classify(Some(3), None, &markers, b""),
+            ExecutionOutcome::NonZeroExit(3)
+        );
+    }
+}
@@ -0,0 +1,58 @@
+/// Everything the trampoline needs to know about a run, sent to it over stdin
+///
+/// The payload is a sequence of frames, each frame is a header line
+/// `<kind> <length>\n` followed by exactly `length` bytes of data. The last
+/// frame is always `end 0\n`. The reading side lives in `runner/trampoline.rs`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Payload {
+    frames: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl Payload {
+    /// Starts a payload for the given source code
+    pub fn new(source: &str) -> Self {
+        let mut payload = Payload::default();
+        payload.frame("source", source);
+        payload
+    }
+
+    /// Appends a frame, `kind` must not contain whitespace
+    pub fn frame(&mut self, kind: &'static str, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.frames.push((kind, data.into()));
+        self
+    }
+
+    /// Serialises the payload into what gets written to the trampoline's stdin
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for (kind, data) in &self.frames {
+            encoded.extend_from_slice(format!("{} {}\n", kind, data.len()).as_bytes());
+            encoded.extend_from_slice(data);
+        }
+        encoded.extend_from_slice(b"end 0\n");
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_frames() {
+        let mut payload = Payload::new("fn main() {}\n");
+        payload.frame("extra", "a\nb");
+        assert_eq!(
+            payload.encode(),
+            b"source 13\nfn main() {}\nextra 3\na\nbend 0\n"
+        );
+    }
+
+    #[test]
+    fn lengths_are_in_bytes() {
+        assert_eq!(
+            Payload::new("🦀").encode(),
+            b"source 4\n\xf0\x9f\xa6\x80end 0\n"
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::Read;
+use std::path::{Component, Path};
+
+use crate::model::crates::{detect_crates, AllowedCrate};
+use crate::model::payload::Payload;
+use crate::model::toolchain::{Channel, Edition, Mode};
+
+/// Most files a project can consist of
+const MAX_FILES: usize = 64;
+
+/// Deepest a file can be nested in a project
+const MAX_DEPTH: usize = 8;
+
+/// A cargo project put together from message attachments
+#[derive(Debug, Clone, Default)]
+pub struct Project {
+    pub files: Vec<ProjectFile>,
+    /// Arguments passed to the program, as seen by `std::env::args()`
+    pub args: Vec<String>,
+    pub channel: Channel,
+    pub edition: Edition,
+    pub mode: Mode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectFile {
+    /// Relative to the root of the project, always separated by `/`
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+/// Why a project was refused, meant to be shown to the user
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProjectError(pub String);
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl StdError for ProjectError {}
+
+impl Project {
+    /// Total size of every file in the project
+    pub fn size(&self) -> usize {
+        self.files.iter().map(|file| file.contents.len()).sum()
+    }
+
+    /// Adds a file after checking its path and that the project stays within
+    /// `max_size` bytes
+    pub fn add_file(
+        &mut self,
+        path: &str,
+        contents: Vec<u8>,
+        max_size: usize,
+    ) -> Result<(), ProjectError> {
+        let path = validate_path(path)?;
+        if self.files.iter().any(|file| file.path == path) {
+            return Err(ProjectError(format!("`{}` was uploaded twice", path)));
+        }
+        if self.files.len() >= MAX_FILES {
+            return Err(ProjectError(format!(
+                "Projects can have at most {} files",
+                MAX_FILES
+            )));
+        }
+        if self.size() + contents.len() > max_size {
+            return Err(ProjectError(format!(
+                "Projects can be at most {} bytes once unpacked",
+                max_size
+            )));
+        }
+        self.files.push(ProjectFile { path, contents });
+        Ok(())
+    }
+
+    /// Adds an uploaded file, archives are unpacked, `.rs` files go into `src/`
+    /// and manifests into the root of the project
+    pub fn add_attachment(
+        &mut self,
+        filename: &str,
+        contents: Vec<u8>,
+        max_size: usize,
+    ) -> Result<(), ProjectError> {
+        let lowercase = filename.to_lowercase();
+        if lowercase.ends_with(".zip") {
+            self.add_zip(contents, max_size)
+        } else if lowercase.ends_with(".tar.gz") || lowercase.ends_with(".tgz") {
+            self.add_tar(flate2::read::GzDecoder::new(&contents[..]), max_size)
+        } else if lowercase.ends_with(".tar") {
+            self.add_tar(&contents[..], max_size)
+        } else if lowercase.ends_with(".rs") {
+            self.add_file(&format!("src/{}", filename), contents, max_size)
+        } else if filename == "Cargo.toml" || filename == "Cargo.lock" {
+            self.add_file(filename, contents, max_size)
+        } else {
+            Err(ProjectError(format!(
+                "Don't know what to do with `{}`, upload a .zip, .tar, .tar.gz, .rs files or a Cargo.toml",
+                filename
+            )))
+        }
+    }
+
+    fn add_zip(&mut self, contents: Vec<u8>, max_size: usize) -> Result<(), ProjectError> {
+        let unreadable = |e| ProjectError(format!("Could not read the zip archive: {}", e));
+        let mut archive =
+            zip::ZipArchive::new(std::io::Cursor::new(contents)).map_err(unreadable)?;
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index).map_err(unreadable)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let path = entry.name().to_string();
+            let contents = read_limited(entry, max_size.saturating_sub(self.size()))?;
+            self.add_file(&path, contents, max_size)?;
+        }
+        Ok(())
+    }
+
+    fn add_tar(&mut self, contents: impl Read, max_size: usize) -> Result<(), ProjectError> {
+        let unreadable = |e| ProjectError(format!("Could not read the tarball: {}", e));
+        let mut archive = tar::Archive::new(contents);
+        for entry in archive.entries().map_err(unreadable)? {
+            let entry = entry.map_err(unreadable)?;
+            let kind = entry.header().entry_type();
+            if kind.is_dir() || kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+                continue;
+            }
+            let path = entry
+                .path()
+                .map_err(unreadable)?
+                .to_string_lossy()
+                .into_owned();
+            if !kind.is_file() {
+                return Err(ProjectError(format!(
+                    "`{}` is not a regular file, links and devices are not allowed",
+                    path
+                )));
+            }
+            let contents = read_limited(entry, max_size.saturating_sub(self.size()))?;
+            self.add_file(&path, contents, max_size)?;
+        }
+        Ok(())
+    }
+
+    /// Tidies the project up once every attachment has been added, archives
+    /// usually wrap everything in a single directory which is removed
+    pub fn finish(&mut self) -> Result<(), ProjectError> {
+        let root = self
+            .files
+            .first()
+            .and_then(|file| file.path.split_once('/'))
+            .map(|(root, _)| format!("{}/", root));
+        if let Some(root) = root {
+            if root != "src/" && self.files.iter().all(|file| file.path.starts_with(&root)) {
+                for file in &mut self.files {
+                    file.path.drain(..root.len());
+                }
+            }
+        }
+
+        // Loose files next to the manifest, `main.rs` and friends belong in src/
+        let has = |path: &str| self.files.iter().any(|file| file.path == path);
+        if !has("src/main.rs") && has("main.rs") {
+            for file in &mut self.files {
+                if file.path.ends_with(".rs") && !file.path.contains('/') {
+                    file.path.insert_str(0, "src/");
+                }
+            }
+        }
+
+        if !self.files.iter().any(|file| file.path == "src/main.rs") {
+            return Err(ProjectError(String::from(
+                "The project needs a `src/main.rs`, or a `main.rs` next to the other files",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Everything that goes into `src/`, used to find the crates it needs
+    fn sources(&self) -> String {
+        self.files
+            .iter()
+            .filter(|file| file.path.ends_with(".rs"))
+            .map(|file| String::from_utf8_lossy(&file.contents))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Builds the payload the trampoline reads from its stdin, `crates` are the
+    /// ones the image has available
+    pub fn payload(&self, crates: &[AllowedCrate]) -> Payload {
+        let mut payload = Payload::default();
+        payload
+            .frame("channel", self.channel.to_string())
+            .frame("edition", self.edition.to_string())
+            .frame("mode", self.mode.to_string());
+        // A project that brings its own manifest decides its own dependencies,
+        // only the vendored ones will resolve though
+        if !self.files.iter().any(|file| file.path == "Cargo.toml") {
+            for dependency in detect_crates(&self.sources(), crates) {
+                payload.frame("crate", dependency.frame());
+            }
+        }
+        for file in &self.files {
+            let mut data = format!("{}\n", file.path).into_bytes();
+            data.extend_from_slice(&file.contents);
+            payload.frame("file", data);
+        }
+        for arg in &self.args {
+            payload.frame("arg", arg.as_str());
+        }
+        payload
+    }
+}
+
+/// Checks that a path stays inside the project and only uses boring characters
+fn validate_path(path: &str) -> Result<String, ProjectError> {
+    let invalid = || ProjectError(format!("`{}` is not an allowed path", path));
+    let mut components = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str().ok_or_else(invalid)?;
+                let boring = name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+                if !boring || name.starts_with('.') {
+                    return Err(invalid());
+                }
+                components.push(name);
+            }
+            Component::CurDir => {}
+            _ => return Err(invalid()),
+        }
+    }
+    if components.is_empty() || components.len() > MAX_DEPTH {
+        return Err(invalid());
+    }
+    Ok(components.join("/"))
+}
+
+/// Reads an archive entry, stopping early if it is larger than `limit` so a
+/// small archive can't unpack into gigabytes
+fn read_limited(entry: impl Read, limit: usize) -> Result<Vec<u8>, ProjectError> {
+    let mut contents = Vec::new();
+    entry
+        .take(limit as u64 + 1)
+        .read_to_end(&mut contents)
+        .map_err(|e| ProjectError(format!("Could not unpack the archive: {}", e)))?;
+    if contents.len() > limit {
+        return Err(ProjectError(String::from(
+            "The archive is too large once unpacked",
+        )));
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const LIMIT: usize = 65536;
+
+    #[test]
+    fn rejects_escaping_paths() {
+        for path in [
+            "../main.rs",
+            "/etc/passwd",
+            "src/../../x",
+            ".cargo/config.toml",
+            "a b.rs",
+            "",
+        ] {
+            assert!(validate_path(path).is_err(), "{}", path);
+        }
+        assert_eq!(validate_path("./src/lib.rs").unwrap(), "src/lib.rs");
+    }
+
+    #[test]
+    fn loose_files_go_into_src() {
+        let mut project = Project::default();
+        project
+            .add_attachment("main.rs", b"mod util;".to_vec(), LIMIT)
+            .unwrap();
+        project
+            .add_attachment("util.rs", Vec::new(), LIMIT)
+            .unwrap();
+        project
+            .add_attachment("Cargo.toml", Vec::new(), LIMIT)
+            .unwrap();
+        project.finish().unwrap();
+        let paths: Vec<_> = project.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["src/main.rs", "src/util.rs", "Cargo.toml"]);
+        assert!(project
+            .add_attachment("notes.txt", Vec::new(), LIMIT)
+            .is_err());
+    }
+
+    #[test]
+    fn unpacks_zip_without_its_root() {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.add_directory("hello/", options).unwrap();
+        zip.start_file("hello/Cargo.toml", options).unwrap();
+        zip.write_all(b"[package]").unwrap();
+        zip.start_file("hello/src/main.rs", options).unwrap();
+        zip.write_all(b"fn main() {}").unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        let mut project = Project::default();
+        project.add_attachment("hello.zip", zip, LIMIT).unwrap();
+        project.finish().unwrap();
+        assert_eq!(
+            project.files[1],
+            ProjectFile {
+                path: String::from("src/main.rs"),
+                contents: b"fn main() {}".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn enforces_size_limits() {
+        let mut project = Project::default();
+        project
+            .add_attachment("main.rs", vec![b' '; 10], 16)
+            .unwrap();
+        assert!(project
+            .add_attachment("big.rs", vec![b' '; 10], 16)
+            .is_err());
+
+        // A gzipped tarball that unpacks into something larger than the limit
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(LIMIT as u64 * 4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "src/main.rs", &vec![0; LIMIT * 4][..])
+            .unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&tar.into_inner().unwrap()).unwrap();
+        let tarball = gz.finish().unwrap();
+        assert!(tarball.len() < LIMIT);
+        assert!(Project::default()
+            .add_attachment("bomb.tar.gz", tarball, LIMIT)
+            .is_err());
+    }
+
+    #[test]
+    fn moves_loose_sources_into_src() {
+        let mut project = Project::default();
+        project.add_file("demo/main.rs", Vec::new(), LIMIT).unwrap();
+        project
+            .add_file("demo/Cargo.toml", Vec::new(), LIMIT)
+            .unwrap();
+        project.finish().unwrap();
+        let paths: Vec<_> = project.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["src/main.rs", "Cargo.toml"]);
+    }
+
+    #[test]
+    fn needs_main() {
+        let mut project = Project::default();
+        project.add_attachment("lib.rs", Vec::new(), LIMIT).unwrap();
+        assert!(project.finish().is_err());
+    }
+}
@@ -0,0 +1,581 @@
+// Discord counts its limits in characters, so everything here does too and
+// never cuts a character in two
+
+/// Most characters in the content of a message
+pub const MESSAGE_LIMIT: usize = 2000;
+
+/// Most characters in the title of an embed
+pub const TITLE_LIMIT: usize = 256;
+
+/// Most characters in the name of an embed field
+pub const FIELD_NAME_LIMIT: usize = 256;
+
+/// Most characters in the value of an embed field
+pub const FIELD_VALUE_LIMIT: usize = 1024;
+
+/// Most characters in the description of an embed
+pub const DESCRIPTION_LIMIT: usize = 4096;
+
+/// Most characters in the footer of an embed
+pub const FOOTER_LIMIT: usize = 2048;
+
+/// Most characters in an embed, counting its title, fields and footer
+pub const EMBED_LIMIT: usize = 6000;
+
+/// Most fields in an embed
+pub const MAX_FIELDS: usize = 25;
+
+/// Characters kept free for the line saying what was left out of long text
+const OMISSION_RESERVE: usize = 48;
+
+/// Put between backticks so a run of them can't end a code block
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+
+/// Shortens `text` to at most `limit` characters, marking the cut with an
+/// ellipsis
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(limit.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Breaks up runs of backticks so `text` can't close the code block it is put
+/// in, the result looks the same
+pub fn escape_fences(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut previous = None;
+    for c in text.chars() {
+        if c == '`' && previous == Some('`') {
+            escaped.push(ZERO_WIDTH_SPACE);
+        }
+        escaped.push(c);
+        previous = Some(c);
+    }
+    escaped
+}
+
+/// Links that invite to a server, matched without caring about case
+const INVITE_LINKS: &[&str] = &[
+    "discord.gg/",
+    "discord.com/invite/",
+    "discordapp.com/invite/",
+];
+
+/// Breaks up `@everyone`, `@here`, user, role and channel mentions and invite
+/// links, so they show up as text but can't ping or embed an invite
+pub fn defuse(text: &str) -> String {
+    let mut defused = String::with_capacity(text.len());
+    let lowercase = text.to_ascii_lowercase();
+    for (i, c) in text.char_indices() {
+        defused.push(c);
+        let rest = &lowercase[i..];
+        let breaks_here = match c {
+            '@' => rest.starts_with("@everyone") || rest.starts_with("@here"),
+            '<' => rest.starts_with("<@") || rest.starts_with("<#"),
+            'd' | 'D' => INVITE_LINKS.iter().any(|link| rest.starts_with(link)),
+            _ => false,
+        };
+        if breaks_here {
+            defused.push(ZERO_WIDTH_SPACE);
+        }
+    }
+    defused
+}
+
+/// Text from code or a program as the content of a message, defused and short
+/// enough to send
+pub fn message_content(text: &str) -> String {
+    truncate(&defuse(text), MESSAGE_LIMIT)
+}
+
+/// How many characters of text fit in a code block of at most `limit`
+/// characters
+fn code_block_budget(language: &str, limit: usize) -> usize {
+    limit.saturating_sub("```\n\n```".len() + language.chars().count())
+}
+
+/// Whether `text` fits in a code block of at most `limit` characters without
+/// being shortened
+pub fn fits_code_block(text: &str, language: &str, limit: usize) -> bool {
+    let text = escape_fences(&defuse(text.trim_end_matches('\n')));
+    text.chars().count() <= code_block_budget(language, limit)
+}
+
+/// Puts `text` in a code block of at most `limit` characters, highlighted as
+/// `language`. Text too long for it is shortened to its first and last lines
+pub fn code_block(text: &str, language: &str, limit: usize) -> String {
+    // Anything but a plain word would end up in the code
+    let language = if language.chars().all(|c| c.is_ascii_alphanumeric()) {
+        language
+    } else {
+        ""
+    };
+    let text = escape_fences(&defuse(text));
+    format!(
+        "```{}\n{}\n```",
+        language,
+        head_and_tail(&text, code_block_budget(language, limit))
+    )
+}
+
+/// Shortens text longer than `budget` characters to its first and last lines,
+/// with a line in between saying how much was left out
+pub fn head_and_tail(text: &str, budget: usize) -> String {
+    let text = text.trim_end_matches('\n');
+    if text.chars().count() <= budget {
+        return text.to_string();
+    }
+
+    let half = budget.saturating_sub(OMISSION_RESERVE) / 2;
+
+    // Cut on character boundaries and around escape sequences, then back to
+    // whole lines when there are any
+    let mut head_end = text.char_indices().nth(half).map_or(text.len(), |(i, _)| i);
+    if let Some((start, _)) = escape_around(text, head_end) {
+        head_end = start;
+    }
+    let mut head = &text[..head_end];
+    if let Some(newline) = head.rfind('\n') {
+        head = &head[..newline];
+    }
+    let mut tail_start = text
+        .char_indices()
+        .rev()
+        .take(half)
+        .last()
+        .map_or(text.len(), |(i, _)| i);
+    if let Some((_, end)) = escape_around(text, tail_start) {
+        tail_start = end;
+    }
+    let mut tail = &text[tail_start..];
+    if let Some(newline) = tail.find('\n') {
+        tail = &tail[newline + 1..];
+    }
+
+    let lines = text.lines().count();
+    // Lines cut in two count as shown
+    let omitted_lines = lines.saturating_sub(head.lines().count() + tail.lines().count());
+    let omission = if omitted_lines > 0 {
+        format!("[... {} of {} lines omitted ...]", omitted_lines, lines)
+    } else {
+        let omitted = text.chars().count() - head.chars().count() - tail.chars().count();
+        format!("[... {} characters omitted ...]", omitted)
+    };
+    format!("{}\n{}\n{}", head, omission, tail)
+}
+
+/// The start and end of the CSI sequence, like the `\x1b[31m` of colours,
+/// that a cut at byte `at` of `text` would split, if any
+fn escape_around(text: &str, at: usize) -> Option<(usize, usize)> {
+    let start = text[..at].rfind(ESCAPE)?;
+    let sequence = text[start + 1..].strip_prefix('[')?;
+    // Parameters and intermediates until a final byte
+    let end = sequence
+        .find(|c| ('\x40'..='\x7e').contains(&c))
+        .map_or(text.len(), |i| text.len() - sequence.len() + i + 1);
+    (at < end).then_some((start, end))
+}
+
+/// Fits fields into an embed whose title and footer take up `reserved`
+/// characters. Names and values are shortened to their limits, fields that
+/// don't fit anymore are left out
+pub fn fit_fields<N: AsRef<str>>(
+    fields: Vec<(N, String, bool)>,
+    reserved: usize,
+) -> Vec<(String, String, bool)> {
+    let mut remaining = EMBED_LIMIT.saturating_sub(reserved);
+    let mut fitted = Vec::new();
+    for (name, value, inline) in fields.into_iter().take(MAX_FIELDS) {
+        let name = truncate(name.as_ref(), FIELD_NAME_LIMIT);
+        let value = truncate(&value, FIELD_VALUE_LIMIT);
+        let size = name.chars().count() + value.chars().count();
+        if size > remaining {
+            break;
+        }
+        remaining -= size;
+        fitted.push((name, value, inline));
+    }
+    fitted
+}
+
+/// Starts every terminal escape sequence
+const ESCAPE: char = '\x1b';
+
+/// Puts program output in a code block of at most `limit` characters. Output
+/// with terminal colours gets an ```ansi block showing the ones Discord
+/// supports
+pub fn output_block(output: &str, limit: usize) -> String {
+    if output.contains(ESCAPE) {
+        code_block(&ansi_to_discord(output), "ansi", limit)
+    } else {
+        code_block(output, "", limit)
+    }
+}
+
+/// Whether [`output_block`] shows all of `output`
+pub fn output_fits(output: &str, limit: usize) -> bool {
+    if output.contains(ESCAPE) {
+        fits_code_block(&ansi_to_discord(output), "ansi", limit)
+    } else {
+        fits_code_block(output, "", limit)
+    }
+}
+
+/// Keeps the SGR sequences of `text` that Discord's ansi blocks understand,
+/// bold, underline and the 8 basic colours. Bright and 256 colours become the
+/// basic ones, anything else is removed
+pub fn ansi_to_discord(text: &str) -> String {
+    map_escapes(text, |parameters| {
+        let codes = discord_sgr(parameters);
+        if codes.is_empty() {
+            String::new()
+        } else {
+            format!("{}[{}m", ESCAPE, codes.join(";"))
+        }
+    })
+}
+
+/// Removes every terminal escape sequence from `text`
+pub fn strip_ansi(text: &str) -> String {
+    map_escapes(text, |_| String::new())
+}
+
+/// Replaces each SGR sequence of `text` with what `sgr` makes of its
+/// parameters and removes every other escape sequence
+fn map_escapes(text: &str, sgr: impl Fn(&str) -> String) -> String {
+    let mut mapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(ESCAPE) {
+        mapped.push_str(&rest[..start]);
+        let sequence = &rest[start + 1..];
+        let mut chars = sequence.char_indices();
+        let end = match chars.next() {
+            // CSI, parameters and intermediates until a final byte
+            Some((_, '[')) => {
+                let end = chars.find(|&(_, c)| ('\x40'..='\x7e').contains(&c));
+                match end {
+                    Some((i, 'm')) => {
+                        mapped.push_str(&sgr(&sequence[1..i]));
+                        i + 1
+                    }
+                    Some((i, _)) => i + 1,
+                    None => sequence.len(),
+                }
+            }
+            // OSC, ends with BEL or ESC \
+            Some((_, ']')) => {
+                let bel = sequence.find('\x07').map(|i| i + 1);
+                let st = sequence.find("\x1b\\").map(|i| i + 2);
+                bel.into_iter().chain(st).min().unwrap_or(sequence.len())
+            }
+            Some((_, c)) => c.len_utf8(),
+            None => 0,
+        };
+        rest = &sequence[end..];
+    }
+    mapped.push_str(rest);
+    mapped
+}
+
+/// The SGR codes of `parameters` that Discord supports
+fn discord_sgr(parameters: &str) -> Vec<String> {
+    let parameters: Vec<u32> = parameters
+        .split(';')
+        .map(|p| p.parse().unwrap_or(0))
+        .collect();
+    let mut codes = Vec::new();
+    let mut i = 0;
+    while i < parameters.len() {
+        match parameters[i] {
+            code @ (0 | 1 | 4 | 30..=37 | 40..=47) => codes.push(code),
+            code @ 90..=97 => codes.push(code - 60),
+            code @ 100..=107 => codes.push(code - 60),
+            // 256 colours start with the basic and bright ones
+            base @ (38 | 48) if parameters.get(i + 1) == Some(&5) => {
+                if let Some(&colour) = parameters.get(i + 2).filter(|&&colour| colour < 16) {
+                    codes.push(base - 8 + colour % 8);
+                }
+                i += 2;
+            }
+            // True colour has no equivalent
+            38 | 48 if parameters.get(i + 1) == Some(&2) => i += 4,
+            _ => {}
+        }
+        i += 1;
+    }
+    codes.iter().map(u32::to_string).collect()
+}
+
+/// Bytes of output per line of a hex dump
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// Turns the output of a program into text. Invalid UTF-8 is replaced with �
+/// and a line saying so is put in front, binary output is shown as a hex dump
+/// instead when `hex_dump` is set
+///
+/// Output cut off in the middle of a character just loses that character.
+pub fn decode_output(bytes: &[u8], hex_dump: bool) -> String {
+    let bytes = match std::str::from_utf8(bytes) {
+        Ok(text) => return text.to_string(),
+        // An incomplete character at the very end, everything before it is fine
+        Err(e) if e.error_len().is_none() => &bytes[..e.valid_up_to()],
+        Err(_) => bytes,
+    };
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+
+    if hex_dump && is_binary(bytes) {
+        return format!(
+            "[binary output, shown as a hex dump]\n{}",
+            self::hex_dump(bytes)
+        );
+    }
+    format!(
+        "[output is not valid UTF-8, invalid bytes are shown as \u{FFFD}]\n{}",
+        String::from_utf8_lossy(bytes)
+    )
+}
+
+/// Whether output looks like binary data rather than text with a few bad bytes
+fn is_binary(bytes: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(bytes);
+    let total = text.chars().count();
+    let odd = text
+        .chars()
+        .filter(|&c| {
+            c == '\u{FFFD}' || (c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x1b'))
+        })
+        .count();
+    text.contains('\0') || odd * 10 > total
+}
+
+/// Dumps bytes like `hexdump -C`, offset, hex bytes and printable characters
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(HEX_DUMP_WIDTH).enumerate() {
+        dump.push_str(&format!("{:08x} ", line * HEX_DUMP_WIDTH));
+        for i in 0..HEX_DUMP_WIDTH {
+            if i % 8 == 0 {
+                dump.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str(" |");
+        dump.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_between_characters() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("crabs", 4), "cra…");
+        // Every one of these is several bytes, cutting by bytes would panic
+        let truncated = truncate(&"🦀é日".repeat(10), 7);
+        assert_eq!(truncated, "🦀é日🦀é日…");
+        assert_eq!(truncate("anything", 0), "…");
+    }
+
+    #[test]
+    fn fences_cannot_escape_the_block() {
+        let block = code_block("```\n@everyone\n```rust", "", FIELD_VALUE_LIMIT);
+        assert_eq!(block.matches("```").count(), 2);
+        assert!(block.starts_with("```\n"));
+        assert!(block.ends_with("\n```"));
+        assert_eq!(escape_fences("a ` b `` c"), "a ` b `\u{200B}` c");
+        assert_eq!(escape_fences("````").replace(ZERO_WIDTH_SPACE, ""), "````");
+    }
+
+    #[test]
+    fn code_blocks_respect_the_limit() {
+        for text in [
+            "x".repeat(5000),
+            "`".repeat(3000),
+            "🦀\n".repeat(2000),
+            "a\r\nb".repeat(700),
+            format!("{}\n{}", "long ".repeat(400), "line\n".repeat(400)),
+        ] {
+            let block = code_block(&text, "rs", FIELD_VALUE_LIMIT);
+            assert!(block.chars().count() <= FIELD_VALUE_LIMIT, "{:?}", text);
+            assert_eq!(block.matches("```").count(), 2);
+            assert!(!fits_code_block(&text, "rs", FIELD_VALUE_LIMIT));
+        }
+        let exact = "x".repeat(FIELD_VALUE_LIMIT - "```\n\n```".len());
+        assert!(fits_code_block(&exact, "", FIELD_VALUE_LIMIT));
+        assert_eq!(
+            code_block(&exact, "", FIELD_VALUE_LIMIT).len(),
+            FIELD_VALUE_LIMIT
+        );
+    }
+
+    #[test]
+    fn odd_languages_are_dropped() {
+        assert_eq!(code_block("x", "rs", 100), "```rs\nx\n```");
+        assert_eq!(code_block("x", "a\n@here", 100), "```\nx\n```");
+    }
+
+    #[test]
+    fn long_text_keeps_head_and_tail() {
+        let text: String = (1..=500).map(|i| format!("line {}\n", i)).collect();
+        let shortened = head_and_tail(&text, 1000);
+        assert!(shortened.chars().count() <= 1000);
+        assert!(shortened.starts_with("line 1\nline 2\n"));
+        assert!(shortened.ends_with("line 499\nline 500"));
+        assert!(shortened.contains(" of 500 lines omitted ...]\n"));
+
+        let shortened = head_and_tail(&"🦀".repeat(2000), 100);
+        assert!(shortened.chars().count() <= 100);
+        assert!(shortened.contains("characters omitted"));
+    }
+
+    #[test]
+    fn long_text_is_not_cut_inside_colours() {
+        let text = "\x1b[1;31mred\x1b[0m \x1b[32mgreen\x1b[0m ".repeat(100);
+        // Wherever the cuts land, every escape left is a whole one
+        for budget in 60..100 {
+            let shortened = head_and_tail(&text, budget);
+            assert!(shortened.contains("characters omitted"));
+            for (i, _) in shortened.match_indices('\x1b') {
+                let sequence = &shortened[i + 1..];
+                let end = sequence.find('m').unwrap();
+                assert!(sequence[..end].starts_with('['));
+                assert!(sequence[1..end]
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ';'));
+            }
+            assert!(!shortened.contains("\n[0m") && !shortened.contains("\n;31m"));
+        }
+    }
+
+    #[test]
+    fn fields_fit_in_the_embed() {
+        let fields = vec![("Code", "x".repeat(5000), true); 40];
+        let fitted = fit_fields(fields, 100);
+        assert_eq!(fitted[0].1.chars().count(), FIELD_VALUE_LIMIT);
+        let total: usize = fitted
+            .iter()
+            .map(|(name, value, _)| name.chars().count() + value.chars().count())
+            .sum();
+        assert!(total + 100 <= EMBED_LIMIT);
+        assert_eq!(fitted.len(), 5);
+
+        let fitted = fit_fields(vec![("n".repeat(300), String::new(), false)], 0);
+        assert_eq!(fitted[0].0.chars().count(), FIELD_NAME_LIMIT);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_without_panicking() {
+        assert_eq!(decode_output(b"plain", true), "plain");
+        let decoded = decode_output(b"ok \xff\xfe then more text", true);
+        assert!(decoded.starts_with("[output is not valid UTF-8"));
+        assert!(decoded.ends_with("ok \u{FFFD}\u{FFFD} then more text"));
+        // Cut off halfway through a crab
+        assert_eq!(decode_output(&"🦀🦀".as_bytes()[..6], true), "🦀");
+        assert_eq!(decode_output(&[0xf0], false), "");
+    }
+
+    #[test]
+    fn binary_output_is_hex_dumped() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let decoded = decode_output(&bytes, true);
+        assert!(decoded.starts_with("[binary output, shown as a hex dump]\n"));
+        assert_eq!(decoded.lines().count(), 1 + 256 / HEX_DUMP_WIDTH);
+        assert!(decode_output(&bytes, false).starts_with("[output is not valid UTF-8"));
+
+        assert_eq!(
+            hex_dump(b"Hello\0\xff"),
+            "00000000  48 65 6c 6c 6f 00 ff                              |Hello..|\n"
+        );
+    }
+
+    #[test]
+    fn mentions_and_invites_are_defused() {
+        for text in [
+            "@everyone",
+            "@here",
+            "hey <@123456789012345678>",
+            "<@!123456789012345678>",
+            "<@&123456789012345678> role",
+            "<#123456789012345678>",
+            "join discord.gg/rust",
+            "https://Discord.com/invite/rust",
+            "discordapp.com/invite/rust",
+        ] {
+            let defused = defuse(text);
+            assert_ne!(defused, text);
+            // Nothing changes but the invisible space
+            assert_eq!(defused.replace(ZERO_WIDTH_SPACE, ""), text);
+            for rendered in [
+                code_block(text, "", FIELD_VALUE_LIMIT),
+                message_content(text),
+            ] {
+                assert!(!rendered.contains("@everyone"));
+                assert!(!rendered.contains("@here"));
+                assert!(!rendered.contains("<@"));
+                assert!(!rendered.contains("<#"));
+                assert!(!rendered.to_lowercase().contains("discord.gg/"));
+                assert!(!rendered.to_lowercase().contains("discord.com/invite/"));
+                assert!(!rendered.contains("discordapp.com/invite/"));
+            }
+        }
+        assert_eq!(
+            defuse("me@example.com <b> discord"),
+            "me@example.com <b> discord"
+        );
+    }
+
+    #[test]
+    fn keeps_colours_discord_supports() {
+        let rustc = "\x1b[0m\x1b[1m\x1b[91merror[E0308]\x1b[0m\x1b[1m: mismatched types\x1b[0m";
+        assert_eq!(
+            ansi_to_discord(rustc),
+            "\x1b[0m\x1b[1m\x1b[31merror[E0308]\x1b[0m\x1b[1m: mismatched types\x1b[0m"
+        );
+        assert_eq!(
+            ansi_to_discord("\x1b[1;38;5;12mx\x1b[m"),
+            "\x1b[1;34mx\x1b[0m"
+        );
+        assert_eq!(
+            ansi_to_discord("\x1b[3;38;2;1;2;3;4mx\x1b[48;5;200my"),
+            "\x1b[4mxy"
+        );
+    }
+
+    #[test]
+    fn removes_other_escapes() {
+        let text = "\x1b[2J\x1b[Hclear\x1b]0;title\x07 \x1b7saved\x1b[";
+        assert_eq!(ansi_to_discord(text), "clear saved");
+        assert_eq!(strip_ansi("\x1b[1;31merror\x1b[0m: 🦀"), "error: 🦀");
+        assert_eq!(strip_ansi("trailing \x1b"), "trailing ");
+    }
+
+    #[test]
+    fn coloured_output_gets_an_ansi_block() {
+        assert_eq!(
+            output_block("\x1b[31mred\x1b[0m", FIELD_VALUE_LIMIT),
+            "```ansi\n\x1b[31mred\x1b[0m\n```"
+        );
+        assert_eq!(output_block("plain", FIELD_VALUE_LIMIT), "```\nplain\n```");
+        // Colours don't count towards what is shown
+        let coloured = "\x1b[38;2;1;2;3mx".repeat(500);
+        assert!(output_fits(&coloured, FIELD_VALUE_LIMIT));
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serenity::model::id::{ChannelId, MessageId};
+use serenity::model::user::User;
+
+/// The reply the bot posted for a run started from a message
+#[derive(Debug, Clone)]
+pub struct TrackedReply {
+    pub channel: ChannelId,
+    pub reply: MessageId,
+    /// Who started the run, edits run on their behalf
+    pub author: User,
+    /// Which of the rust code blocks of the message was run
+    pub block: usize,
+    /// The code that was run, edits that leave it alone are ignored
+    pub code: String,
+    /// Whether the message gets a reaction showing how the run went
+    pub react: bool,
+    /// The reaction currently on the message
+    pub reaction: Option<char>,
+    tracked_at: Instant,
+}
+
+impl TrackedReply {
+    pub fn new(
+        channel: ChannelId,
+        reply: MessageId,
+        author: User,
+        block: usize,
+        code: String,
+        react: bool,
+    ) -> Self {
+        TrackedReply {
+            channel,
+            reply,
+            author,
+            block,
+            code,
+            react,
+            reaction: None,
+            tracked_at: Instant::now(),
+        }
+    }
+}
+
+/// Remembers which reply belongs to which message, so editing the message can
+/// re-run it and deleting it can clean up after it
+///
+/// Only recent runs are remembered, older ones are forgotten once there are
+/// more than `capacity` or they are older than `max_age`.
+pub struct ReplyTracker {
+    replies: Mutex<HashMap<MessageId, TrackedReply>>,
+    capacity: usize,
+    max_age: Duration,
+}
+
+impl ReplyTracker {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        ReplyTracker {
+            replies: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+            max_age,
+        }
+    }
+
+    /// Remembers that `reply` was posted for a run of `source`, replacing
+    /// whatever was remembered for it before
+    pub fn track(&self, source: MessageId, mut reply: TrackedReply) {
+        reply.tracked_at = Instant::now();
+        let mut replies = self.replies.lock().unwrap();
+        let max_age = self.max_age;
+        replies.retain(|_, tracked| tracked.tracked_at.elapsed() < max_age);
+        if replies.len() >= self.capacity && !replies.contains_key(&source) {
+            let oldest = replies
+                .iter()
+                .min_by_key(|(_, tracked)| tracked.tracked_at)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                replies.remove(&oldest);
+            }
+        }
+        replies.insert(source, reply);
+    }
+
+    /// The reply posted for `source`, if it is still remembered
+    pub fn get(&self, source: MessageId) -> Option<TrackedReply> {
+        let replies = self.replies.lock().unwrap();
+        replies
+            .get(&source)
+            .filter(|tracked| tracked.tracked_at.elapsed() < self.max_age)
+            .cloned()
+    }
+
+    /// Forgets `source`, returning its reply
+    pub fn remove(&self, source: MessageId) -> Option<TrackedReply> {
+        self.replies.lock().unwrap().remove(&source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(tracker: &ReplyTracker, source: u64) {
+        let reply = TrackedReply::new(
+            ChannelId(1),
+            MessageId(source + 100),
+            User::default(),
+            0,
+            String::new(),
+            false,
+        );
+        tracker.track(MessageId(source), reply);
+    }
+
+    #[test]
+    fn remembers_replies() {
+        let tracker = ReplyTracker::new(10, Duration::from_secs(60));
+        track(&tracker, 1);
+        assert_eq!(tracker.get(MessageId(1)).unwrap().reply, MessageId(101));
+        assert!(tracker.get(MessageId(2)).is_none());
+        assert_eq!(tracker.remove(MessageId(1)).unwrap().reply, MessageId(101));
+        assert!(tracker.get(MessageId(1)).is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_when_full() {
+        let tracker = ReplyTracker::new(2, Duration::from_secs(60));
+        track(&tracker, 1);
+        std::thread::sleep(Duration::from_millis(2));
+        track(&tracker, 2);
+        std::thread::sleep(Duration::from_millis(2));
+        track(&tracker, 3);
+        assert!(tracker.get(MessageId(1)).is_none());
+        assert!(tracker.get(MessageId(2)).is_some());
+        assert!(tracker.get(MessageId(3)).is_some());
+    }
+
+    #[test]
+    fn forgets_old_replies() {
+        let tracker = ReplyTracker::new(10, Duration::ZERO);
+        track(&tracker, 1);
+        assert!(tracker.get(MessageId(1)).is_none());
+    }
+}
@@ -1,69 +1,296 @@
 use async_trait::async_trait;
-use process_control::{ChildExt, Control, Output};
 use std::io;
 use std::io::Error;
-use std::time::Duration;
+use std::os::unix::process::ExitStatusExt;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
 
-use crate::model::container::{get_container_settings, ContainerActions, ContainerSettings};
+use crate::model::container::{
+    get_container_settings, unique_container_name, ContainerActions, ContainerGuard,
+    ContainerSettings,
+};
+use crate::model::crates::{detect_crates, AllowedCrate};
+use crate::model::explain::ErrorCode;
+use crate::model::outcome::{ExecutionOutcome, ExecutionResult, Markers, Timings};
+use crate::model::payload::Payload;
+use crate::model::project::Project;
+use crate::model::toolchain::{Channel, Edition, Mode};
 
+/// Runs something in the sandbox, if `live` is given the output is published
+/// to it as it is produced
 #[async_trait]
 pub trait Runnable {
-    async fn run(&self) -> Result<Output, Error>;
+    async fn run(&self, live: Option<watch::Sender<LiveOutput>>) -> Result<ExecutionResult, Error>;
     async fn run_with_settings(
         &self,
         container_settings: ContainerSettings,
-    ) -> Result<Output, Error>;
+        live: Option<watch::Sender<LiveOutput>>,
+    ) -> Result<ExecutionResult, Error>;
+}
+
+/// Output produced so far by a run that is still going, stderr still contains
+/// the trampoline markers
+#[derive(Debug, Clone, Default)]
+pub struct LiveOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Source code together with the input it should be run with
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: String,
+    /// Arguments passed to the program, as seen by `std::env::args()`
+    pub args: Vec<String>,
+    /// Data available to the program on its stdin
+    pub stdin: Option<String>,
+    pub channel: Channel,
+    pub edition: Edition,
+    pub mode: Mode,
+    /// Extra rustc flags, one entry per flag, already checked against the allowlist
+    pub rustc_flags: Vec<String>,
+}
+
+impl From<String> for Program {
+    fn from(code: String) -> Self {
+        Program {
+            code,
+            ..Default::default()
+        }
+    }
+}
+
+impl Program {
+    /// Builds the payload the trampoline reads from its stdin, `crates` are the
+    /// ones the image has available
+    fn payload(&self, crates: &[AllowedCrate]) -> Payload {
+        let mut payload = Payload::new(&self.code);
+        payload
+            .frame("channel", self.channel.to_string())
+            .frame("edition", self.edition.to_string())
+            .frame("mode", self.mode.to_string());
+        // Only crates the code refers to end up in the manifest, so programs
+        // without dependencies keep compiling with plain rustc
+        for dependency in detect_crates(&self.code, crates) {
+            payload.frame("crate", dependency.frame());
+        }
+        for flag in &self.rustc_flags {
+            payload.frame("rustc-flag", flag.as_str());
+        }
+        for arg in &self.args {
+            payload.frame("arg", arg.as_str());
+        }
+        if let Some(stdin) = &self.stdin {
+            payload.frame("stdin", stdin.as_str());
+        }
+        payload
+    }
 }
 
 #[async_trait]
 impl Runnable for String {
-    async fn run(&self) -> Result<Output, Error> {
+    async fn run(&self, live: Option<watch::Sender<LiveOutput>>) -> Result<ExecutionResult, Error> {
+        Program::from(self.clone()).run(live).await
+    }
+
+    async fn run_with_settings(
+        &self,
+        container_settings: ContainerSettings,
+        live: Option<watch::Sender<LiveOutput>>,
+    ) -> Result<ExecutionResult, Error> {
+        Program::from(self.clone())
+            .run_with_settings(container_settings, live)
+            .await
+    }
+}
+
+#[async_trait]
+impl Runnable for Program {
+    async fn run(&self, live: Option<watch::Sender<LiveOutput>>) -> Result<ExecutionResult, Error> {
+        let settings = get_container_settings();
+        self.run_with_settings(settings, live).await
+    }
+
+    async fn run_with_settings(
+        &self,
+        container_settings: ContainerSettings,
+        live: Option<watch::Sender<LiveOutput>>,
+    ) -> Result<ExecutionResult, Error> {
+        let payload = self.payload(&container_settings.crates);
+        execute(payload, container_settings, live).await
+    }
+}
+
+#[async_trait]
+impl Runnable for Project {
+    async fn run(&self, live: Option<watch::Sender<LiveOutput>>) -> Result<ExecutionResult, Error> {
         let settings = get_container_settings();
-        self.run_with_settings(settings).await
+        self.run_with_settings(settings, live).await
     }
 
     async fn run_with_settings(
         &self,
         container_settings: ContainerSettings,
-    ) -> Result<Output, Error> {
-        // TODO: the original rustbot had support for running with arguments, may be worth adding this in the future
-        // https://github.com/TheConner/RustBot/blob/main/src/commands/run.rs#L37-L41
-
-        // In order to run an arbitrary string with the current design, we have to first base64 the content
-        // and then run the command with the base64'd content as stdin.
-        let encoded_program = base64::encode(&self);
-
-        // Next, we have to build the command that invokes the trampoline inside the container
-        let container_command = format!("trampoline {}", encoded_program);
-
-        //println!("{}", container_command);
-
-        // Because std::command does not give me the ability to override / modify
-        // how arguments are escaped I have to do some stupid hack to make this
-        // work. For example, if I wanted to run
-        // podman run rustbot:latest ls -al
-        // this would be impossible if I did
-        //
-        //  std::process::Command::new("podman")
-        //    .args(["run", "rustbot:latest", "ls -al"])
-        //    .output()
-        //    .expect("failed to invoke container");
-        //
-        // As the ls -al would be quoted, and the container would try to execute
-        // `ls -al` which would fail. The alternative is to seperate "ls", "-al"
-        // which would also fail as the container would run `ls` then `-al`
-        // ... what a stupid design
-        // So instead of embracing the safety this API gives you, i'm just invoking
-        // a shell with a payload I deem as safe
-        let process = container_settings.invoke_command(container_command);
-
-        let output = process?
-            .controlled_with_output()
-            .time_limit(Duration::from_millis(container_settings.max_runtime))
-            .terminate_for_timeout()
-            .wait()?
-            .ok_or_else(|| Error::new(io::ErrorKind::TimedOut, "Process timed out"));
-
-        output
+        live: Option<watch::Sender<LiveOutput>>,
+    ) -> Result<ExecutionResult, Error> {
+        let payload = self.payload(&container_settings.crates);
+        execute(payload, container_settings, live).await
+    }
+}
+
+#[async_trait]
+impl Runnable for ErrorCode {
+    async fn run(&self, live: Option<watch::Sender<LiveOutput>>) -> Result<ExecutionResult, Error> {
+        let settings = get_container_settings();
+        self.run_with_settings(settings, live).await
+    }
+
+    async fn run_with_settings(
+        &self,
+        container_settings: ContainerSettings,
+        live: Option<watch::Sender<LiveOutput>>,
+    ) -> Result<ExecutionResult, Error> {
+        execute(self.payload(), container_settings, live).await
+    }
+}
+
+/// Hands a payload to the trampoline in a fresh container and collects what
+/// comes out, within the limits of `container_settings`
+async fn execute(
+    payload: Payload,
+    container_settings: ContainerSettings,
+    live: Option<watch::Sender<LiveOutput>>,
+) -> Result<ExecutionResult, Error> {
+    // When nobody is watching, the live output just goes nowhere
+    let live = live.unwrap_or_else(|| watch::channel(LiveOutput::default()).0);
+
+    // The program is sent to the trampoline over stdin, so it never ends up on a
+    // command line where it would be limited by ARG_MAX and visible in `ps`
+    let payload = payload.encode();
+
+    // Refuse oversized programs before spending a container on them
+    if payload.len() as u64 > container_settings.max_program_size {
+        return Err(Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Your program and its input add up to {} bytes, the limit is {} bytes.",
+                payload.len(),
+                container_settings.max_program_size
+            ),
+        ));
+    }
+
+    let started = Instant::now();
+
+    // Every run gets its own container name so it can be killed if it outlives us
+    let name = unique_container_name();
+
+    let mut process = container_settings.invoke_command(&name, "trampoline", Vec::new())?;
+
+    // From here on, the container is killed if we bail out for any reason
+    let guard = ContainerGuard::new(container_settings.clone(), name.clone(), process.id());
+
+    let mut stdin = process.stdin.take().expect("stdin is piped");
+    let stdout = process.stdout.take().expect("stdout is piped");
+    let stderr = process.stderr.take().expect("stderr is piped");
+    let limit = container_settings.max_output_size as usize;
+    let mut stdout_buffer = Vec::new();
+    let mut stderr_buffer = Vec::new();
+
+    let finished = tokio::time::timeout(
+        Duration::from_millis(container_settings.max_runtime),
+        async {
+            // The payload is written while output is read, the container could fill
+            // its stdout pipe before it has read everything. If the program dies
+            // early the write fails, its exit status tells the story
+            let write_payload = async {
+                let _ = stdin.write_all(&payload).await;
+                drop(stdin);
+                Ok(())
+            };
+            tokio::try_join!(
+                write_payload,
+                capture(stdout, &mut stdout_buffer, limit, |chunk| {
+                    live.send_modify(|live| live.stdout.extend_from_slice(chunk))
+                }),
+                capture(stderr, &mut stderr_buffer, limit, |chunk| {
+                    live.send_modify(|live| live.stderr.extend_from_slice(chunk))
+                }),
+            )?;
+            process.wait().await.map_err(Stop::Io)
+        },
+    )
+    .await;
+
+    let status = match finished {
+        Ok(Ok(status)) => Ok(status),
+        Ok(Err(Stop::Io(e))) => return Err(e),
+        Ok(Err(Stop::OutputLimit)) => Err(ExecutionOutcome::OutputLimitExceeded),
+        Err(_) => Err(ExecutionOutcome::Timeout),
+    };
+
+    let (markers, stderr) = Markers::extract(&stderr_buffer);
+    let outcome = match status {
+        Ok(status) => {
+            guard.disarm();
+            ExecutionOutcome::classify(
+                status.code().map(i64::from),
+                status.signal(),
+                &markers,
+                &stderr,
+            )
+        }
+        Err(outcome) => {
+            // Killing the client process is not enough, the container has to go
+            // too. The run is over either way, so a failed kill is only logged
+            if let Err(e) = guard.kill().await {
+                println!("Error killing container {}: {:?}", name, e);
+            }
+            outcome
+        }
+    };
+
+    Ok(ExecutionResult {
+        outcome,
+        stdout: stdout_buffer,
+        stderr,
+        build_output: markers.build_output,
+        timings: Timings {
+            compile: markers.compile_time,
+            total: started.elapsed(),
+        },
+    })
+}
+
+/// Why capturing the output of a run stopped early
+enum Stop {
+    OutputLimit,
+    Io(Error),
+}
+
+/// Reads a stream into `buffer`, giving up as soon as it has produced more than
+/// `limit` bytes so a chatty program can't eat all of our memory. Every chunk
+/// that is kept is also handed to `on_chunk`
+async fn capture(
+    mut stream: impl AsyncRead + Unpin,
+    buffer: &mut Vec<u8>,
+    limit: usize,
+    on_chunk: impl Fn(&[u8]),
+) -> Result<(), Stop> {
+    let mut chunk = [0; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await.map_err(Stop::Io)?;
+        if read == 0 {
+            return Ok(());
+        }
+        if buffer.len() + read > limit {
+            let kept = &chunk[..limit - buffer.len()];
+            buffer.extend_from_slice(kept);
+            on_chunk(kept);
+            return Err(Stop::OutputLimit);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        on_chunk(&chunk[..read]);
     }
 }
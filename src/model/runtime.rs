@@ -0,0 +1,67 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+/// The backend used to execute untrusted code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Runtime {
+    /// Run code in a podman container (the default)
+    Podman,
+    /// Run code in a docker container
+    Docker,
+    /// Run the trampoline directly on the host as a regular process, no
+    /// isolation is provided by the bot. This is meant for hosts which wrap
+    /// the bot in their own sandbox (bubblewrap, nsjail, ...) or for local
+    /// development
+    Local,
+}
+
+#[derive(Debug)]
+pub struct ParseRuntimeError(pub String);
+
+impl fmt::Display for ParseRuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown container runtime {}", self.0)
+    }
+}
+
+impl StdError for ParseRuntimeError {}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Podman => write!(f, "podman"),
+            Self::Docker => write!(f, "docker"),
+            Self::Local => write!(f, "local"),
+        }
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = ParseRuntimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "podman" => Ok(Self::Podman),
+            "docker" => Ok(Self::Docker),
+            "local" => Ok(Self::Local),
+            _ => Err(ParseRuntimeError(s.to_string())),
+        }
+    }
+}
+
+impl Runtime {
+    /// The CLI used to drive this runtime, if there is one
+    pub fn binary(&self) -> Option<&'static str> {
+        match self {
+            Self::Podman => Some("podman"),
+            Self::Docker => Some("docker"),
+            Self::Local => None,
+        }
+    }
+
+    /// Whether this runtime can enforce the resource limits in `ContainerSettings`
+    pub fn enforces_limits(&self) -> bool {
+        !matches!(self, Self::Local)
+    }
+}
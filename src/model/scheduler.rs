@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// Decides when runs get to start so a busy channel can't spawn an unbounded
+/// amount of containers on one host
+///
+/// At most `max_concurrent` runs execute at once, everything else waits in a
+/// single FIFO queue. Each user can only have `max_per_user` runs in flight
+/// (queued or running), so nobody can push everyone else to the back.
+pub struct Scheduler {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    max_concurrent: usize,
+    max_per_user: usize,
+    running: usize,
+    in_flight: HashMap<u64, usize>,
+    queue: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    start: oneshot::Sender<()>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TooManyRunsError(pub usize);
+
+impl fmt::Display for TooManyRunsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "You already have {} runs in progress, wait for them to finish.",
+            self.0
+        )
+    }
+}
+
+impl StdError for TooManyRunsError {}
+
+impl Inner {
+    /// Frees the slot of a run that finished and starts whoever is next
+    fn release(&mut self, user: u64) {
+        self.running -= 1;
+        self.leave(user);
+        while self.running < self.max_concurrent {
+            match self.queue.pop_front() {
+                Some(waiter) => {
+                    self.running += 1;
+                    // The ticket removes itself from the queue before dropping its
+                    // receiver, so this can't fail
+                    let _ = waiter.start.send(());
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn leave(&mut self, user: u64) {
+        if let Some(count) = self.in_flight.get_mut(&user) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&user);
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize, max_per_user: usize) -> Self {
+        Scheduler {
+            inner: Arc::new(Mutex::new(Inner {
+                max_concurrent: max_concurrent.max(1),
+                max_per_user: max_per_user.max(1),
+                running: 0,
+                in_flight: HashMap::new(),
+                queue: VecDeque::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// Puts a run for `user` in line, the returned ticket has to be waited on
+    /// before starting the run
+    pub fn enqueue(&self, user: u64) -> Result<Ticket, TooManyRunsError> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+
+        let in_flight = inner.in_flight.entry(user).or_default();
+        if *in_flight >= inner.max_per_user {
+            return Err(TooManyRunsError(*in_flight));
+        }
+        *in_flight += 1;
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let start = if inner.running < inner.max_concurrent && inner.queue.is_empty() {
+            inner.running += 1;
+            None
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            inner.queue.push_back(Waiter { id, start: sender });
+            Some(receiver)
+        };
+
+        Ok(Ticket {
+            inner: self.inner.clone(),
+            id,
+            user,
+            start,
+            done: false,
+        })
+    }
+}
+
+/// A place in the queue
+pub struct Ticket {
+    inner: Arc<Mutex<Inner>>,
+    id: u64,
+    user: u64,
+    start: Option<oneshot::Receiver<()>>,
+    done: bool,
+}
+
+impl Ticket {
+    /// Position in the queue starting at 1, or 0 if the run can start right away
+    pub fn position(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .queue
+            .iter()
+            .position(|waiter| waiter.id == self.id)
+            .map_or(0, |position| position + 1)
+    }
+
+    /// Waits for our turn, the run may start once this returns
+    pub async fn wait(mut self) -> Permit {
+        if let Some(start) = self.start.take() {
+            // The sender lives in the queue, which is never dropped while we hold it
+            let _ = start.await;
+        }
+        self.done = true;
+        Permit {
+            inner: self.inner.clone(),
+            user: self.user,
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        // Given up on before the run started, e.g. the command was cancelled
+        let mut inner = self.inner.lock().unwrap();
+        match inner.queue.iter().position(|waiter| waiter.id == self.id) {
+            Some(position) => {
+                inner.queue.remove(position);
+                inner.leave(self.user);
+            }
+            // We were already handed a slot, pass it on
+            None => inner.release(self.user),
+        }
+    }
+}
+
+/// Held for as long as a run executes, dropping it lets the next run start
+pub struct Permit {
+    inner: Arc<Mutex<Inner>>,
+    user: u64,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().release(self.user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_start_in_order() {
+        let scheduler = Scheduler::new(1, 2);
+        let first = scheduler.enqueue(1).unwrap();
+        let second = scheduler.enqueue(2).unwrap();
+        let third = scheduler.enqueue(3).unwrap();
+        assert_eq!(
+            (first.position(), second.position(), third.position()),
+            (0, 1, 2)
+        );
+
+        let permit = first.wait().await;
+        drop(permit);
+        assert_eq!(third.position(), 1);
+        let _permit = second.wait().await;
+        assert_eq!(third.position(), 1);
+    }
+
+    #[tokio::test]
+    async fn per_user_limit() {
+        let scheduler = Scheduler::new(4, 1);
+        let ticket = scheduler.enqueue(1).unwrap();
+        assert_eq!(scheduler.enqueue(1).err(), Some(TooManyRunsError(1)));
+        assert!(scheduler.enqueue(2).is_ok());
+
+        drop(ticket.wait().await);
+        assert!(scheduler.enqueue(1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelled_tickets_leave_the_queue() {
+        let scheduler = Scheduler::new(1, 1);
+        let running = scheduler.enqueue(1).unwrap().wait().await;
+        let cancelled = scheduler.enqueue(2).unwrap();
+        let waiting = scheduler.enqueue(3).unwrap();
+        drop(cancelled);
+        assert_eq!(waiting.position(), 1);
+
+        drop(running);
+        assert_eq!(waiting.position(), 0);
+        assert!(scheduler.enqueue(2).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Release channel of the toolchain a program is compiled with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Channel {
+    #[default]
+    #[name = "stable"]
+    Stable,
+    #[name = "beta"]
+    Beta,
+    #[name = "nightly"]
+    Nightly,
+}
+
+/// Edition a program is compiled with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Edition {
+    #[name = "2015"]
+    E2015,
+    #[name = "2018"]
+    E2018,
+    #[default]
+    #[name = "2021"]
+    E2021,
+    #[name = "2024"]
+    E2024,
+}
+
+/// Whether a program is compiled with optimisations, like cargo's profiles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Mode {
+    #[default]
+    #[name = "debug"]
+    Debug,
+    #[name = "release"]
+    Release,
+}
+
+/// Flags that take their value as a separate argument
+const FLAGS_WITH_VALUE: &[&str] = &["-C", "-Z", "-A", "-W", "-D", "--cfg", "--cap-lints"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DisallowedFlagError(pub String);
+
+impl fmt::Display for DisallowedFlagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The rustc flag `{}` is not allowed", self.0)
+    }
+}
+
+impl StdError for DisallowedFlagError {}
+
+/// Splits a line of rustc flags into one entry per flag, so `-C opt-level=3`,
+/// `-Copt-level=3` and `-C  opt-level=3` all come out as `-C opt-level=3`
+pub fn normalise_rustc_flags(line: &str) -> Vec<String> {
+    let mut flags = Vec::new();
+    let mut tokens = line.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if FLAGS_WITH_VALUE.contains(&token) {
+            match tokens.next() {
+                Some(value) => flags.push(format!("{} {}", token, value)),
+                None => flags.push(token.to_string()),
+            }
+            continue;
+        }
+
+        // Short flags can have their value glued on, e.g. -Copt-level=3
+        match FLAGS_WITH_VALUE
+            .iter()
+            .filter(|flag| flag.len() == 2)
+            .find(|flag| token.starts_with(*flag) && token.len() > 2)
+        {
+            Some(flag) => flags.push(format!("{} {}", flag, &token[2..])),
+            None => flags.push(token.to_string()),
+        }
+    }
+    flags
+}
+
+/// Parses user supplied rustc flags, refusing anything that isn't allowlisted
+///
+/// Allowlist entries are flags in the same form, an entry ending in `*`
+/// allows any flag starting with what comes before it
+pub fn parse_rustc_flags(
+    line: &str,
+    allowlist: &[String],
+) -> Result<Vec<String>, DisallowedFlagError> {
+    let flags = normalise_rustc_flags(line);
+    for flag in &flags {
+        let allowed = allowlist.iter().any(|entry| match entry.strip_suffix('*') {
+            Some(prefix) => flag.starts_with(prefix),
+            None => flag == entry,
+        });
+        if !allowed {
+            return Err(DisallowedFlagError(flag.clone()));
+        }
+    }
+    Ok(flags)
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stable => write!(f, "stable"),
+            Self::Beta => write!(f, "beta"),
+            Self::Nightly => write!(f, "nightly"),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Debug => write!(f, "debug"),
+            Self::Release => write!(f, "release"),
+        }
+    }
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::E2015 => write!(f, "2015"),
+            Self::E2018 => write!(f, "2018"),
+            Self::E2021 => write!(f, "2021"),
+            Self::E2024 => write!(f, "2024"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalises_flags() {
+        assert_eq!(
+            normalise_rustc_flags("-C overflow-checks=off  -Ctarget-cpu=native --cfg foo -O"),
+            [
+                "-C overflow-checks=off",
+                "-C target-cpu=native",
+                "--cfg foo",
+                "-O"
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_the_allowlist() {
+        let allowlist = vec![
+            String::from("-C overflow-checks=off"),
+            String::from("-C target-cpu=*"),
+        ];
+        assert_eq!(
+            parse_rustc_flags("-Coverflow-checks=off -C target-cpu=native", &allowlist),
+            Ok(vec![
+                String::from("-C overflow-checks=off"),
+                String::from("-C target-cpu=native")
+            ])
+        );
+        assert_eq!(
+            parse_rustc_flags("-C overflow-checks=on", &allowlist),
+            Err(DisallowedFlagError(String::from("-C overflow-checks=on")))
+        );
+        assert_eq!(
+            parse_rustc_flags("-C link-arg=-Wl,foo", &allowlist),
+            Err(DisallowedFlagError(String::from("-C link-arg=-Wl,foo")))
+        );
+    }
+}